@@ -0,0 +1,192 @@
+use rand::{seq::IteratorRandom, Rng};
+use std::time::Duration;
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// Every third rechoke, so the optimistic unchoke gets a fair 30 seconds to
+// show what it can do.
+pub const OPTIMISTIC_ROUNDS: u32 = 3;
+// A peer with requests outstanding that sends nothing for this long is snubbing
+// us and loses its regular slot.
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+// What the choker knows about a peer, keyed by whatever identifies the peer to
+// the caller. Rates are in bytes per second.
+#[derive(Debug, Clone)]
+pub struct PeerStats<K> {
+    pub key: K,
+    // The peer is interested in us; nobody else is worth unchoking.
+    pub interested: bool,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub snubbed: bool,
+}
+
+// Picks the `slots` interested peers that reciprocate best: those we download
+// fastest from while leeching, or those we upload fastest to while seeding.
+// Snubbing peers get no regular slot while leeching. Ties keep their order.
+pub fn choose_unchoked<K: Copy>(peers: &[PeerStats<K>], slots: usize, seeding: bool) -> Vec<K> {
+    let rate = |peer: &PeerStats<K>| {
+        if seeding {
+            peer.upload_rate
+        } else {
+            peer.download_rate
+        }
+    };
+    let mut ranked: Vec<_> = peers
+        .iter()
+        .filter(|peer| peer.interested && (seeding || !peer.snubbed))
+        .collect();
+    ranked.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+    ranked
+        .into_iter()
+        .take(slots)
+        .map(|peer| peer.key)
+        .collect()
+}
+
+// Picks an interested peer that didn't make the cut for the optimistic slot,
+// uniformly at random.
+pub fn choose_optimistic<K: Copy + PartialEq>(
+    peers: &[PeerStats<K>],
+    unchoked: &[K],
+    rng: &mut impl Rng,
+) -> Option<K> {
+    peers
+        .iter()
+        .filter(|peer| peer.interested && !unchoked.contains(&peer.key))
+        .map(|peer| peer.key)
+        .choose(rng)
+}
+
+// Tit-for-tat with one optimistic unchoke on top of the regular slots. Call
+// `rechoke` every RECHOKE_INTERVAL; it rotates the optimistic unchoke every
+// OPTIMISTIC_ROUNDS calls or when the current one lost interest or left.
+pub struct Choker<K> {
+    slots: usize,
+    optimistic: Option<K>,
+    rounds: u32,
+}
+
+impl<K: Copy + PartialEq> Choker<K> {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            optimistic: None,
+            rounds: 0,
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn optimistic(&self) -> Option<K> {
+        self.optimistic
+    }
+
+    // Returns the peers to unchoke; every other peer should be choked.
+    pub fn rechoke(&mut self, peers: &[PeerStats<K>], seeding: bool, rng: &mut impl Rng) -> Vec<K> {
+        let mut unchoked = choose_unchoked(peers, self.slots, seeding);
+        let still_wanted = self.optimistic.is_some_and(|key| {
+            peers
+                .iter()
+                .any(|peer| peer.key == key && peer.interested && !unchoked.contains(&key))
+        });
+        if !still_wanted || self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            self.optimistic = choose_optimistic(peers, &unchoked, rng);
+            self.rounds = 0;
+        }
+        self.rounds += 1;
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn peer(key: usize, download_rate: f64, upload_rate: f64) -> PeerStats<usize> {
+        PeerStats {
+            key,
+            interested: true,
+            download_rate,
+            upload_rate,
+            snubbed: false,
+        }
+    }
+
+    #[test]
+    fn ranks_by_the_rate_that_reciprocates() {
+        let peers = [
+            peer(0, 10.0, 300.0),
+            peer(1, 30.0, 100.0),
+            peer(2, 20.0, 200.0),
+        ];
+        assert_eq!(choose_unchoked(&peers, 2, false), [1, 2]);
+        assert_eq!(choose_unchoked(&peers, 2, true), [0, 2]);
+    }
+
+    #[test]
+    fn leaves_out_snubbing_peers_while_leeching() {
+        let mut peers = [
+            peer(0, 10.0, 10.0),
+            peer(1, 30.0, 30.0),
+            peer(2, 20.0, 20.0),
+        ];
+        peers[1].snubbed = true;
+        assert_eq!(choose_unchoked(&peers, 2, false), [2, 0]);
+        assert_eq!(choose_unchoked(&peers, 2, true), [1, 2]);
+    }
+
+    #[test]
+    fn leaves_out_uninterested_peers() {
+        let mut peers = [
+            peer(0, 10.0, 10.0),
+            peer(1, 30.0, 30.0),
+            peer(2, 20.0, 20.0),
+        ];
+        peers[1].interested = false;
+        peers[2].interested = false;
+        assert_eq!(choose_unchoked(&peers, 2, false), [0]);
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(choose_optimistic(&peers, &[0], &mut rng), None);
+    }
+
+    #[test]
+    fn rotates_the_optimistic_unchoke_every_few_rounds() {
+        // Peer 0 holds the only regular slot; the rest take turns.
+        let peers: Vec<_> = (0..10)
+            .map(|key| peer(key, (10 - key) as f64, 0.0))
+            .collect();
+        let mut choker = Choker::new(1);
+        let mut rng = StdRng::seed_from_u64(1);
+        let picks: Vec<_> = (0..30)
+            .map(|_| {
+                let unchoked = choker.rechoke(&peers, false, &mut rng);
+                assert_eq!(unchoked[0], 0);
+                assert_eq!(unchoked.len(), 2);
+                choker.optimistic().unwrap()
+            })
+            .collect();
+        for round in 1..picks.len() {
+            if !(round as u32).is_multiple_of(OPTIMISTIC_ROUNDS) {
+                assert_eq!(picks[round], picks[round - 1], "round {}", round);
+            }
+        }
+        assert!(picks.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn replaces_an_optimistic_unchoke_that_lost_interest() {
+        let mut peers: Vec<_> = (0..4).map(|key| peer(key, 0.0, 0.0)).collect();
+        let mut choker = Choker::new(0);
+        let mut rng = StdRng::seed_from_u64(1);
+        choker.rechoke(&peers, false, &mut rng);
+        let first = choker.optimistic().unwrap();
+        peers[first].interested = false;
+        choker.rechoke(&peers, false, &mut rng);
+        assert_ne!(choker.optimistic(), Some(first));
+    }
+}
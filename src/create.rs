@@ -0,0 +1,352 @@
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::decode::BencodeValue;
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024; // 16 KiB
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024; // 16 MiB
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Default)]
+pub struct CreateOptions {
+    pub trackers: Vec<String>,
+    // `None` picks a power of two from the total size.
+    pub piece_length: Option<u32>,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+// Builds a bencoded .torrent for the file or directory at `path`. Keys are
+// written in sorted order and files in byte order of their joined paths, so the
+// info-hash matches what mktorrent and libtorrent-based clients produce.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> anyhow::Result<Vec<u8>> {
+    // Resolves `.`, `..` and symlinks so there is always a real name to use.
+    let path = &path
+        .canonicalize()
+        .with_context(|| format!("cannot open {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("path must end in a valid UTF-8 file name")?
+        .to_string();
+    let metadata = std::fs::metadata(path)?;
+    let mut files = Vec::new();
+    if metadata.is_dir() {
+        collect_files(path, &mut Vec::new(), &mut files)?;
+        files.sort_by_cached_key(|f| f.components.join("/"));
+    } else {
+        files.push(SourceFile {
+            path: path.to_path_buf(),
+            components: vec![],
+            length: metadata.len(),
+        });
+    }
+
+    let total: u64 = files.iter().map(|f| f.length).sum();
+    anyhow::ensure!(total > 0, "nothing to hash: {} is empty", path.display());
+    let piece_length = match options.piece_length {
+        Some(len) => len,
+        None => auto_piece_length(total),
+    };
+    anyhow::ensure!(
+        piece_length.is_power_of_two() && piece_length as u64 >= MIN_PIECE_LENGTH,
+        "piece length must be a power of two of at least {} bytes, got {}",
+        MIN_PIECE_LENGTH,
+        piece_length
+    );
+    let pieces = hash_pieces(&files, total, piece_length)?;
+
+    let mut info = vec![
+        ("name", BencodeValue::Bytes(name.into_bytes())),
+        ("piece length", BencodeValue::Int(piece_length as i64)),
+        ("pieces", BencodeValue::Bytes(pieces)),
+    ];
+    if metadata.is_dir() {
+        let files = files
+            .into_iter()
+            .map(|f| {
+                BencodeValue::dict(vec![
+                    ("length", BencodeValue::Int(f.length as i64)),
+                    (
+                        "path",
+                        BencodeValue::List(
+                            f.components
+                                .into_iter()
+                                .map(|c| BencodeValue::Bytes(c.into_bytes()))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+        info.push(("files", BencodeValue::List(files)));
+    } else {
+        info.push(("length", BencodeValue::Int(total as i64)));
+    }
+    if options.private {
+        info.push(("private", BencodeValue::Int(1)));
+    }
+    if let Some(source) = &options.source {
+        info.push(("source", BencodeValue::bytes(source)));
+    }
+
+    let mut torrent = vec![("info", BencodeValue::dict(info))];
+    if let Some(first) = options.trackers.first() {
+        torrent.push(("announce", BencodeValue::bytes(first)));
+    }
+    if options.trackers.len() > 1 {
+        // One tracker per tier, tried in the order given.
+        let tiers = options
+            .trackers
+            .iter()
+            .map(|t| BencodeValue::List(vec![BencodeValue::bytes(t)]))
+            .collect();
+        torrent.push(("announce-list", BencodeValue::List(tiers)));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.push(("comment", BencodeValue::bytes(comment)));
+    }
+    if let Some(created_by) = &options.created_by {
+        torrent.push(("created by", BencodeValue::bytes(created_by)));
+    }
+    if let Some(date) = options.creation_date {
+        torrent.push(("creation date", BencodeValue::Int(date)));
+    }
+    match options.web_seeds.as_slice() {
+        [] => {}
+        [url] => torrent.push(("url-list", BencodeValue::bytes(url))),
+        urls => torrent.push((
+            "url-list",
+            BencodeValue::List(urls.iter().map(BencodeValue::bytes).collect()),
+        )),
+    }
+    Ok(BencodeValue::dict(torrent).encode())
+}
+
+pub fn auto_piece_length(total: u64) -> u32 {
+    (total / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH) as u32
+}
+
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<SourceFile>,
+) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| anyhow::anyhow!("non UTF-8 file name {:?}", n))?;
+        let mut file_type = entry.file_type()?;
+        // Symlinks are followed, unless they point back at a directory we are inside.
+        if file_type.is_symlink() {
+            let target = entry
+                .path()
+                .canonicalize()
+                .with_context(|| format!("broken symlink {}", entry.path().display()))?;
+            anyhow::ensure!(
+                !dir.canonicalize()?.starts_with(&target),
+                "symlink {} loops back to {}",
+                entry.path().display(),
+                target.display()
+            );
+            file_type = std::fs::metadata(&target)?.file_type();
+        }
+        prefix.push(name);
+        if file_type.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push(SourceFile {
+                path: entry.path(),
+                components: prefix.clone(),
+                length: std::fs::metadata(entry.path())?.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// Splits the pieces into one contiguous run per core and hashes the runs in parallel.
+fn hash_pieces(files: &[SourceFile], total: u64, piece_length: u32) -> anyhow::Result<Vec<u8>> {
+    let num_pieces = total.div_ceil(piece_length as u64) as usize;
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(num_pieces);
+    let per_thread = num_pieces.div_ceil(threads);
+
+    let hashes = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                let range = t * per_thread..((t + 1) * per_thread).min(num_pieces);
+                scope.spawn(move || hash_range(files, total, piece_length, range))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("hashing thread panicked"))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
+    Ok(hashes.concat())
+}
+
+fn hash_range(
+    files: &[SourceFile],
+    total: u64,
+    piece_length: u32,
+    pieces: Range<usize>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut reader = ConcatReader::new(files, pieces.start as u64 * piece_length as u64)?;
+    let mut hashes = Vec::with_capacity(pieces.len() * 20);
+    let mut buf = vec![0u8; piece_length as usize];
+    for piece in pieces {
+        let offset = piece as u64 * piece_length as u64;
+        let len = (total - offset).min(piece_length as u64) as usize;
+        reader.read_exact(&mut buf[..len])?;
+        hashes.extend(Sha1::digest(&buf[..len]));
+    }
+    Ok(hashes)
+}
+
+// Reads the files back to back, as if they were one stream.
+struct ConcatReader<'a> {
+    files: &'a [SourceFile],
+    current: Option<File>,
+    index: usize,
+}
+
+impl<'a> ConcatReader<'a> {
+    fn new(files: &'a [SourceFile], mut offset: u64) -> anyhow::Result<Self> {
+        let mut index = 0;
+        while index < files.len() && offset >= files[index].length {
+            offset -= files[index].length;
+            index += 1;
+        }
+        let mut current = None;
+        if let Some(file) = files.get(index) {
+            let mut handle = File::open(&file.path)?;
+            handle.seek(SeekFrom::Start(offset))?;
+            current = Some(handle);
+        }
+        Ok(Self {
+            files,
+            current,
+            index,
+        })
+    }
+}
+
+impl Read for ConcatReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let Some(current) = &mut self.current else {
+                return Ok(0);
+            };
+            let n = current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.index += 1;
+            self.current = match self.files.get(self.index) {
+                Some(file) => Some(File::open(&file.path)?),
+                None => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    // fixture/a/c.txt is 20000 bytes of 'x', fixture/b.txt is "hello world\n".
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("fixture/a")).unwrap();
+        std::fs::write(dir.path().join("fixture/a/c.txt"), [b'x'; 20000]).unwrap();
+        std::fs::write(dir.path().join("fixture/b.txt"), b"hello world\n").unwrap();
+        dir
+    }
+
+    fn options(piece_length: u32) -> CreateOptions {
+        CreateOptions {
+            trackers: vec!["http://tracker.example/announce".to_string()],
+            piece_length: Some(piece_length),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trips_with_a_known_info_hash() {
+        let dir = fixture();
+        let bytes = create_torrent(&dir.path().join("fixture"), &options(16384)).unwrap();
+        let output = dir.path().join("fixture.torrent");
+        std::fs::write(&output, bytes).unwrap();
+        let torrent = Torrent::new(output).unwrap();
+        // The info dictionary BEP 3 prescribes for this layout, hashed independently.
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "4103240b0adad53de610727846f866efd743ba76"
+        );
+        assert_eq!(torrent.info.name(), "fixture");
+        assert_eq!(torrent.info.num_pieces(), 2);
+        let lengths: Vec<_> = torrent.info.files().iter().map(|f| f.length).collect();
+        assert_eq!(lengths, [20000, 12]);
+    }
+
+    #[test]
+    fn names_dot_after_the_directory() {
+        let dir = fixture();
+        let bytes = create_torrent(&dir.path().join("fixture/a/.."), &options(16384)).unwrap();
+        let output = dir.path().join("fixture.torrent");
+        std::fs::write(&output, bytes).unwrap();
+        let torrent = Torrent::new(output).unwrap();
+        assert_eq!(torrent.info.name(), "fixture");
+    }
+
+    #[test]
+    fn rejects_bad_piece_lengths() {
+        let dir = fixture();
+        for piece_length in [0, 8192, 20000] {
+            assert!(create_torrent(&dir.path().join("fixture"), &options(piece_length)).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_but_not_loops() {
+        let dir = fixture();
+        let root = dir.path().join("fixture");
+        std::os::unix::fs::symlink(root.join("b.txt"), root.join("d.txt")).unwrap();
+        let bytes = create_torrent(&root, &options(16384)).unwrap();
+        let output = dir.path().join("fixture.torrent");
+        std::fs::write(&output, bytes).unwrap();
+        let torrent = Torrent::new(output).unwrap();
+        let lengths: Vec<_> = torrent.info.files().iter().map(|f| f.length).collect();
+        assert_eq!(lengths, [20000, 12, 12]);
+
+        std::os::unix::fs::symlink(&root, root.join("a/up")).unwrap();
+        assert!(create_torrent(&root, &options(16384)).is_err());
+    }
+}
@@ -1,30 +1,446 @@
-pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<serde_json::Value> {
-    let value = serde_bencode::from_str(encoded_value)?;
-    let decoded = bencode_to_json(value)?;
-    Ok(decoded)
+use base64::Engine;
+use std::ops::Range;
+
+// Deeper nesting is refused rather than risking the stack on hostile input;
+// real torrents and KRPC messages stay within a handful of levels.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<BencodeValue>),
+    // Entries stay in source order so that re-encoding reproduces the input.
+    Dict(Vec<(Vec<u8>, BencodeValue)>),
+}
+
+// A decoded value with its byte range in the source, so any part of it can be
+// hashed or re-read exactly as it appears there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned {
+    pub span: Range<usize>,
+    pub value: SpannedValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpannedValue {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<Spanned>),
+    Dict(Vec<(Vec<u8>, Spanned)>),
 }
 
-fn bencode_to_json(value: serde_bencode::value::Value) -> anyhow::Result<serde_json::Value> {
-    match value {
-        serde_bencode::value::Value::Bytes(b) => {
-            Ok(serde_json::Value::String(String::from_utf8(b)?))
+impl Spanned {
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut parser = Parser::new(bytes);
+        let value = parser.parse_value()?;
+        parser.expect_end()?;
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Spanned> {
+        match &self.value {
+            SpannedValue::Dict(d) => d.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
         }
-        serde_bencode::value::Value::Int(i) => {
-            Ok(serde_json::Value::Number(serde_json::Number::from(i)))
+    }
+
+    // The value without its spans.
+    pub fn into_value(self) -> BencodeValue {
+        match self.value {
+            SpannedValue::Bytes(b) => BencodeValue::Bytes(b),
+            SpannedValue::Int(i) => BencodeValue::Int(i),
+            SpannedValue::List(l) => {
+                BencodeValue::List(l.into_iter().map(Spanned::into_value).collect())
+            }
+            SpannedValue::Dict(d) => {
+                BencodeValue::Dict(d.into_iter().map(|(k, v)| (k, v.into_value())).collect())
+            }
         }
-        serde_bencode::value::Value::List(l) => {
-            let json_list = l
-                .into_iter()
-                .map(|v| bencode_to_json(v))
-                .collect::<anyhow::Result<Vec<serde_json::Value>>>()?;
-            Ok(serde_json::Value::Array(json_list))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum BinaryEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl BencodeValue {
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(Spanned::decode(bytes)?.into_value())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Bytes(b) => encode_bytes(b, out),
+            BencodeValue::Int(i) => out.extend(format!("i{}e", i).as_bytes()),
+            BencodeValue::List(l) => {
+                out.push(b'l');
+                l.iter().for_each(|v| v.encode_into(out));
+                out.push(b'e');
+            }
+            BencodeValue::Dict(d) => {
+                out.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(k, out);
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            }
         }
-        serde_bencode::value::Value::Dict(d) => {
-            let json_map = d
+    }
+
+    pub fn bytes(bytes: impl AsRef<[u8]>) -> Self {
+        Self::Bytes(bytes.as_ref().to_vec())
+    }
+
+    // A dictionary with its keys sorted, as bencode requires.
+    pub fn dict(mut entries: Vec<(&str, BencodeValue)>) -> Self {
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        Self::Dict(
+            entries
                 .into_iter()
-                .map(|(k, v)| Ok((String::from_utf8(k)?, bencode_to_json(v)?)))
-                .collect::<anyhow::Result<serde_json::Map<String, serde_json::Value>>>()?;
-            Ok(serde_json::Value::Object(json_map))
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::Dict(d) => d.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self, binary: BinaryEncoding) -> serde_json::Value {
+        match self {
+            BencodeValue::Bytes(b) => bytes_to_json(b, binary),
+            BencodeValue::Int(i) => serde_json::Value::Number(serde_json::Number::from(*i)),
+            BencodeValue::List(l) => {
+                serde_json::Value::Array(l.iter().map(|v| v.to_json(binary)).collect())
+            }
+            // Keys keep their bencoded order.
+            BencodeValue::Dict(d) => serde_json::Value::Object(
+                d.iter()
+                    .map(|(k, v)| (key_to_string(k, binary), v.to_json(binary)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+pub fn decode_bencoded_value(
+    encoded_value: &[u8],
+    binary: BinaryEncoding,
+) -> anyhow::Result<serde_json::Value> {
+    Ok(BencodeValue::decode(encoded_value)?.to_json(binary))
+}
+
+// Returns the byte range of the value found by following `path` through nested
+// dictionaries, so callers can hash or re-read it exactly as it appears in `bytes`.
+pub fn value_span(bytes: &[u8], path: &[&[u8]]) -> anyhow::Result<Range<usize>> {
+    let mut parser = Parser::new(bytes);
+    for key in path {
+        parser.expect(b'd')?;
+        loop {
+            anyhow::ensure!(
+                parser.peek()? != b'e',
+                "key {:?} not found",
+                String::from_utf8_lossy(key)
+            );
+            if parser.parse_bytes()? == *key {
+                break;
+            }
+            parser.skip_value()?;
+        }
+    }
+    let start = parser.pos;
+    parser.skip_value()?;
+    Ok(start..parser.pos)
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+// UTF-8 becomes a string, anything else `{"hex": "..."}` or `{"base64": "..."}`,
+// so binary data can't be mistaken for text that happens to look encoded.
+fn bytes_to_json(bytes: &[u8], binary: BinaryEncoding) -> serde_json::Value {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        return serde_json::Value::String(s.to_string());
+    }
+    let (encoding, encoded) = match binary {
+        BinaryEncoding::Hex => ("hex", hex::encode(bytes)),
+        BinaryEncoding::Base64 => (
+            "base64",
+            base64::engine::general_purpose::STANDARD.encode(bytes),
+        ),
+    };
+    serde_json::Value::Object(
+        [(encoding.to_string(), encoded.into())]
+            .into_iter()
+            .collect(),
+    )
+}
+
+// JSON keys can only be strings, so a binary key is its tagged form as JSON text.
+fn key_to_string(bytes: &[u8], binary: BinaryEncoding) -> String {
+    match bytes_to_json(bytes, binary) {
+        serde_json::Value::String(s) => s,
+        tagged => tagged.to_string(),
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Lists and dictionaries we are inside of.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            depth: 0,
         }
     }
-}
\ No newline at end of file
+
+    fn peek(&self) -> anyhow::Result<u8> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of input at offset {}", self.pos))
+    }
+
+    fn expect(&mut self, byte: u8) -> anyhow::Result<()> {
+        let found = self.peek()?;
+        anyhow::ensure!(
+            found == byte,
+            "expected '{}' at offset {}, found '{}'",
+            byte as char,
+            self.pos,
+            found as char
+        );
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expect_end(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.pos == self.bytes.len(),
+            "trailing data at offset {}",
+            self.pos
+        );
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> anyhow::Result<Spanned> {
+        let start = self.pos;
+        let value = match self.peek()? {
+            b'i' => SpannedValue::Int(self.parse_int()?),
+            b'l' => {
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.parse_value()?);
+                }
+                self.leave();
+                SpannedValue::List(list)
+            }
+            b'd' => {
+                self.enter()?;
+                let mut dict = Vec::new();
+                while self.peek()? != b'e' {
+                    let key = self.parse_bytes()?.to_vec();
+                    dict.push((key, self.parse_value()?));
+                }
+                self.leave();
+                SpannedValue::Dict(dict)
+            }
+            b'0'..=b'9' => SpannedValue::Bytes(self.parse_bytes()?.to_vec()),
+            b => {
+                return Err(anyhow::anyhow!(
+                    "unexpected '{}' at offset {}",
+                    b as char,
+                    self.pos
+                ))
+            }
+        };
+        Ok(Spanned {
+            span: start..self.pos,
+            value,
+        })
+    }
+
+    fn skip_value(&mut self) -> anyhow::Result<()> {
+        match self.peek()? {
+            b'i' => {
+                self.parse_int()?;
+            }
+            b'l' | b'd' => {
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.skip_value()?;
+                }
+                self.leave();
+            }
+            _ => {
+                self.parse_bytes()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Steps into the list or dictionary starting here.
+    fn enter(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.depth < MAX_DEPTH,
+            "nesting deeper than {} at offset {}",
+            MAX_DEPTH,
+            self.pos
+        );
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Steps over the 'e' closing the innermost list or dictionary.
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
+
+    fn parse_int(&mut self) -> anyhow::Result<i64> {
+        self.expect(b'i')?;
+        let digits = self.take_until(b'e')?;
+        // Only canonical integers are accepted, otherwise encoding would not
+        // reproduce the input byte for byte.
+        let canonical = match digits {
+            [b'-', b'0', ..] | [b'0', _, ..] | [] | [b'-'] => false,
+            [b'-', rest @ ..] | rest => rest.iter().all(u8::is_ascii_digit),
+        };
+        anyhow::ensure!(
+            canonical,
+            "invalid integer {:?}",
+            String::from_utf8_lossy(digits)
+        );
+        Ok(std::str::from_utf8(digits)?.parse()?)
+    }
+
+    fn parse_bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let digits = self.take_until(b':')?;
+        anyhow::ensure!(
+            !digits.is_empty()
+                && digits.iter().all(u8::is_ascii_digit)
+                && (digits == b"0" || digits[0] != b'0'),
+            "invalid string length {:?}",
+            String::from_utf8_lossy(digits)
+        );
+        let len: usize = std::str::from_utf8(digits)?.parse()?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow::anyhow!("string at offset {} overruns input", self.pos))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    // Consumes up to and including `terminator`, returning what came before it.
+    fn take_until(&mut self, terminator: u8) -> anyhow::Result<&'a [u8]> {
+        let rest = &self.bytes[self.pos..];
+        let len = rest.iter().position(|b| *b == terminator).ok_or_else(|| {
+            anyhow::anyhow!("missing '{}' after offset {}", terminator as char, self.pos)
+        })?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_point_into_the_source() {
+        let source = b"d4:infod6:lengthi3ee4:listl1:ai-2eee";
+        let decoded = Spanned::decode(source).unwrap();
+        assert_eq!(decoded.span, 0..source.len());
+        let info = decoded.get(b"info").unwrap();
+        assert_eq!(&source[info.span.clone()], b"d6:lengthi3ee");
+        assert_eq!(info.get(b"length").unwrap().span, 16..19);
+        let SpannedValue::List(list) = &decoded.get(b"list").unwrap().value else {
+            panic!("not a list");
+        };
+        assert_eq!(&source[list[0].span.clone()], b"1:a");
+        assert_eq!(&source[list[1].span.clone()], b"i-2e");
+        assert_eq!(
+            decoded.into_value().encode(),
+            source,
+            "spans are dropped without changing the value"
+        );
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(BencodeValue::decode(&nested(MAX_DEPTH)).is_ok());
+        assert!(BencodeValue::decode(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(value_span(&nested(MAX_DEPTH + 1), &[]).is_err());
+        // Far past the limit, where unbounded recursion would overflow.
+        assert!(BencodeValue::decode(&nested(1_000_000)).is_err());
+        assert!(value_span(&nested(1_000_000), &[]).is_err());
+    }
+
+    #[test]
+    fn tags_binary_strings_and_keeps_key_order() {
+        let torrent =
+            std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent")).unwrap();
+        let decoded = BencodeValue::decode(&torrent).unwrap();
+        let pieces = decoded.get(b"info").unwrap().get(b"pieces").unwrap();
+        let json = decoded.to_json(BinaryEncoding::Hex);
+        assert_eq!(
+            json["info"]["pieces"],
+            serde_json::json!({ "hex": hex::encode(pieces.as_bytes().unwrap()) })
+        );
+        let base64 = decoded.to_json(BinaryEncoding::Base64);
+        assert!(base64["info"]["pieces"]["base64"].is_string());
+        let keys: Vec<_> = json["info"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["length", "name", "piece length", "pieces"]);
+
+        let json = decode_bencoded_value(b"d1:b7:hex:abc1:a2:\xff\x00e", BinaryEncoding::Hex);
+        assert_eq!(
+            json.unwrap().to_string(),
+            r#"{"b":"hex:abc","a":{"hex":"ff00"}}"#
+        );
+        let json = decode_bencoded_value(b"d1:\xffi1ee", BinaryEncoding::Hex).unwrap();
+        assert_eq!(json.to_string(), r#"{"{\"hex\":\"ff\"}":1}"#);
+    }
+}
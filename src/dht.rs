@@ -0,0 +1,780 @@
+use anyhow::Context;
+use futures_util::{future::join_all, stream::FuturesUnordered, StreamExt};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    decode::BencodeValue,
+    pex::compact,
+    tracker::{parse_compact_peers, parse_compact_peers6},
+};
+
+// BEP 5: https://www.bittorrent.org/beps/bep_0005.html
+pub const DHT_PORT: u16 = 6881;
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+// How often a download looks for more peers and a seed announces itself again.
+pub const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Nodes per bucket, and how many of the closest nodes a lookup ends with.
+const K: usize = 8;
+// Queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Unanswered queries in a row before a node may be replaced by a newcomer.
+const MAX_FAILURES: u32 = 2;
+// Tokens are made with the current secret; those made with the previous one
+// are still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// Announced peers are forgotten unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// Peers we store per torrent, and hand out per get_peers so the reply fits in
+// a datagram.
+const MAX_STORED_PEERS: usize = 1000;
+const MAX_VALUES: usize = 50;
+
+// KRPC error codes.
+const PROTOCOL_ERROR: i64 = 203;
+const METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    // The XOR metric. Distances compare as big-endian numbers, as arrays do.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+// Compact node info: the id followed by the compact address, 26 bytes for
+// IPv4 nodes ("nodes") and 38 for IPv6 ones ("nodes6", BEP 32).
+fn compact_nodes<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for node in nodes {
+        bytes.extend(node.id.0);
+        bytes.extend(compact([node.address]).into_vec());
+    }
+    bytes
+}
+
+fn parse_nodes(response: &BencodeValue) -> Vec<Node> {
+    let field = |key: &[u8]| response.get(key).and_then(BencodeValue::as_bytes);
+    let nodes4 = field(b"nodes").unwrap_or_default().chunks_exact(26);
+    let nodes6 = field(b"nodes6").unwrap_or_default().chunks_exact(38);
+    nodes4
+        .chain(nodes6)
+        .filter_map(|chunk| {
+            let (id, address) = chunk.split_at(20);
+            let address = match address.len() {
+                6 => parse_compact_peers(address),
+                _ => parse_compact_peers6(address),
+            };
+            let node = Node {
+                id: NodeId::from_bytes(id)?,
+                address: *address.first()?,
+            };
+            (node.address.port() != 0).then_some(node)
+        })
+        .collect()
+}
+
+struct Entry {
+    node: Node,
+    failures: u32,
+}
+
+// Bucket i holds up to K nodes whose ids share exactly i leading bits with
+// ours, least recently seen first.
+struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = self.id.distance(id);
+        let byte = distance.iter().position(|b| *b != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+
+    // Notes that `node` answered or queried us. Nodes that stay responsive
+    // are kept over newcomers, which only take the place of failing ones.
+    fn insert(&mut self, node: Node) {
+        let Some(index) = self.bucket(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|e| e.node.id == node.id) {
+            bucket.remove(position);
+        } else if bucket.len() >= K {
+            match bucket.iter().position(|e| e.failures >= MAX_FAILURES) {
+                Some(position) => {
+                    bucket.remove(position);
+                }
+                None => return,
+            }
+        }
+        bucket.push(Entry { node, failures: 0 });
+    }
+
+    fn failed(&mut self, address: SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.address == address {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    // The good nodes closest to `target`, closest first.
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| target.distance(&node.id));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+// The secrets announce tokens are derived from.
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Secrets {
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+// A token only the node at `ip` gets from us.
+fn token(ip: IpAddr, secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+// A get_peers reply: the peers the node knows, or nodes closer to the torrent.
+pub struct GetPeers {
+    // Needed to announce to the node.
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<Node>,
+}
+
+struct Lookup {
+    // The K closest nodes that answered, with their tokens.
+    closest: Vec<(Node, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+// A query of ours waiting for its reply.
+struct Transaction {
+    address: SocketAddr,
+    reply: oneshot::Sender<anyhow::Result<BencodeValue>>,
+}
+
+// A DHT node on a UDP socket. It answers queries as long as it is alive and
+// finds and announces peers for torrents on request.
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    table: StdMutex<RoutingTable>,
+    transactions: StdMutex<HashMap<[u8; 2], Transaction>>,
+    next_transaction: AtomicU16,
+    // Peers announced to us, by info-hash, with when they announced.
+    peers: StdMutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+    secrets: StdMutex<Secrets>,
+    receiver: JoinHandle<()>,
+}
+
+impl Dht {
+    pub async fn bind(address: SocketAddr) -> anyhow::Result<Arc<Self>> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let id = NodeId::random();
+        Ok(Arc::new_cyclic(|dht| Self {
+            id,
+            socket: socket.clone(),
+            table: StdMutex::new(RoutingTable::new(id)),
+            transactions: StdMutex::default(),
+            next_transaction: AtomicU16::new(rand::random()),
+            peers: StdMutex::default(),
+            secrets: StdMutex::new(Secrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            receiver: tokio::spawn(receive(dht.clone(), socket)),
+        }))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // How many nodes the routing table holds.
+    pub fn node_count(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    // Our socket only reaches nodes of its own address family.
+    fn reachable(&self, address: SocketAddr) -> bool {
+        self.socket
+            .local_addr()
+            .is_ok_and(|local| local.is_ipv4() == address.is_ipv4())
+    }
+
+    // Joins the DHT through the `nodes` given as host:port, then looks up our
+    // own id to fill the routing table. Returns how many nodes we know.
+    pub async fn bootstrap(&self, nodes: &[String]) -> anyhow::Result<usize> {
+        let mut addresses = Vec::new();
+        for node in nodes {
+            match lookup_host(node.as_str()).await {
+                Ok(found) => addresses.extend(found.filter(|a| self.reachable(*a))),
+                Err(e) => eprintln!("{} -> {}", node, e),
+            }
+        }
+        join_all(addresses.iter().map(|address| self.ping(*address))).await;
+        self.lookup(self.id, None).await;
+        let count = self.node_count();
+        anyhow::ensure!(count > 0, "no DHT node answered");
+        Ok(count)
+    }
+
+    // Peers for `info_hash`, from the nodes closest to it.
+    pub async fn find_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(NodeId(info_hash), Some(info_hash)).await.peers
+    }
+
+    // Tells the nodes closest to `info_hash` that we have the torrent on
+    // `port`, or on the port our DHT queries come from if there is none.
+    // Returns the peers found on the way.
+    pub async fn announce(&self, info_hash: [u8; 20], port: Option<u16>) -> Vec<SocketAddr> {
+        let lookup = self.lookup(NodeId(info_hash), Some(info_hash)).await;
+        let announces = lookup.closest.iter().filter_map(|(node, token)| {
+            Some(self.announce_peer(node.address, info_hash, port, token.as_deref()?))
+        });
+        join_all(announces).await;
+        lookup.peers
+    }
+
+    // Asks ever closer nodes for `target` until the K closest we know of
+    // have answered. With an info-hash, asks for its peers too.
+    async fn lookup(&self, target: NodeId, info_hash: Option<[u8; 20]>) -> Lookup {
+        let mut candidates: BTreeMap<_, _> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (target.distance(&node.id), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                let Some((distance, node)) = candidates.pop_first() else {
+                    break;
+                };
+                // Everything left is farther than the K closest that answered.
+                if responded.len() >= K && responded.keys().nth(K - 1) < Some(&distance) {
+                    candidates.clear();
+                    break;
+                }
+                queried.insert(node.address);
+                in_flight.push(async move {
+                    let result = match info_hash {
+                        Some(info_hash) => self.get_peers(node.address, info_hash).await,
+                        None => self
+                            .find_node(node.address, target)
+                            .await
+                            .map(|nodes| GetPeers {
+                                token: None,
+                                peers: Vec::new(),
+                                nodes,
+                            }),
+                    };
+                    (node, result)
+                });
+            }
+            let Some((node, result)) = in_flight.next().await else {
+                break;
+            };
+            let Ok(reply) = result else {
+                continue;
+            };
+            peers.extend(reply.peers);
+            for found in reply.nodes {
+                if found.id != self.id
+                    && self.reachable(found.address)
+                    && !queried.contains(&found.address)
+                {
+                    candidates.insert(target.distance(&found.id), found);
+                }
+            }
+            responded.insert(target.distance(&node.id), (node, reply.token));
+        }
+        Lookup {
+            closest: responded.into_values().take(K).collect(),
+            peers: peers.into_iter().collect(),
+        }
+    }
+
+    pub async fn ping(&self, address: SocketAddr) -> anyhow::Result<NodeId> {
+        let (id, _) = self.query(address, "ping", Vec::new()).await?;
+        Ok(id)
+    }
+
+    pub async fn find_node(
+        &self,
+        address: SocketAddr,
+        target: NodeId,
+    ) -> anyhow::Result<Vec<Node>> {
+        let args = vec![("target", BencodeValue::bytes(target.0))];
+        let (_, response) = self.query(address, "find_node", args).await?;
+        Ok(parse_nodes(&response))
+    }
+
+    pub async fn get_peers(
+        &self,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<GetPeers> {
+        let args = vec![("info_hash", BencodeValue::bytes(info_hash))];
+        let (_, response) = self.query(address, "get_peers", args).await?;
+        let mut peers = Vec::new();
+        if let Some(BencodeValue::List(values)) = response.get(b"values") {
+            for value in values.iter().filter_map(BencodeValue::as_bytes) {
+                match value.len() {
+                    6 => peers.extend(parse_compact_peers(value)),
+                    18 => peers.extend(parse_compact_peers6(value)),
+                    _ => {}
+                }
+            }
+        }
+        peers.retain(|peer| peer.port() != 0);
+        Ok(GetPeers {
+            token: response
+                .get(b"token")
+                .and_then(BencodeValue::as_bytes)
+                .map(<[u8]>::to_vec),
+            peers,
+            nodes: parse_nodes(&response),
+        })
+    }
+
+    // `port` None has the node take the source port of the query instead.
+    pub async fn announce_peer(
+        &self,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+        token: &[u8],
+    ) -> anyhow::Result<()> {
+        let args = vec![
+            ("implied_port", BencodeValue::Int(port.is_none() as i64)),
+            ("info_hash", BencodeValue::bytes(info_hash)),
+            ("port", BencodeValue::Int(port.unwrap_or(0) as i64)),
+            ("token", BencodeValue::bytes(token)),
+        ];
+        self.query(address, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    // Sends a query and waits for the reply and the id of the node that sent
+    // it, keeping the routing table up to date with whether the node answered.
+    async fn query(
+        &self,
+        address: SocketAddr,
+        method: &str,
+        mut args: Vec<(&str, BencodeValue)>,
+    ) -> anyhow::Result<(NodeId, BencodeValue)> {
+        let t = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        args.push(("id", BencodeValue::bytes(self.id.0)));
+        let message = BencodeValue::dict(vec![
+            ("a", BencodeValue::dict(args)),
+            ("q", BencodeValue::bytes(method)),
+            ("t", BencodeValue::bytes(t)),
+            ("y", BencodeValue::bytes("q")),
+        ]);
+        let (reply, response) = oneshot::channel();
+        self.transactions
+            .lock()
+            .unwrap()
+            .insert(t, Transaction { address, reply });
+        let reply = match self.socket.send_to(&message.encode(), address).await {
+            Ok(_) => timeout(QUERY_TIMEOUT, response)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(_) => None,
+        };
+        self.transactions.lock().unwrap().remove(&t);
+        let Some(reply) = reply else {
+            self.table.lock().unwrap().failed(address);
+            anyhow::bail!("{} got no reply from {}", method, address);
+        };
+        // An error reply still shows the node is alive.
+        let response = reply?;
+        let id = response_id(&response).context("reply without a node id")?;
+        self.table.lock().unwrap().insert(Node { id, address });
+        Ok((id, response))
+    }
+
+    async fn handle(&self, from: SocketAddr, message: BencodeValue) {
+        let Some(t) = message.get(b"t").and_then(BencodeValue::as_bytes) else {
+            return;
+        };
+        match message.get(b"y").and_then(BencodeValue::as_bytes) {
+            Some(b"q") => {
+                let reply = match self.answer(from, &message) {
+                    Ok(r) => BencodeValue::dict(vec![
+                        ("r", r),
+                        ("t", BencodeValue::bytes(t)),
+                        ("y", BencodeValue::bytes("r")),
+                    ]),
+                    Err((code, e)) => BencodeValue::dict(vec![
+                        (
+                            "e",
+                            BencodeValue::List(vec![
+                                BencodeValue::Int(code),
+                                BencodeValue::bytes(e),
+                            ]),
+                        ),
+                        ("t", BencodeValue::bytes(t)),
+                        ("y", BencodeValue::bytes("e")),
+                    ]),
+                };
+                let _ = self.socket.send_to(&reply.encode(), from).await;
+            }
+            Some(b"r") => {
+                let response = message
+                    .get(b"r")
+                    .filter(|r| response_id(r).is_some())
+                    .cloned()
+                    .context("malformed reply");
+                self.complete(t, from, response);
+            }
+            Some(b"e") => {
+                let error = match message.get(b"e") {
+                    Some(BencodeValue::List(e)) => format!(
+                        "KRPC error {}: {}",
+                        e.first().and_then(BencodeValue::as_int).unwrap_or(0),
+                        String::from_utf8_lossy(
+                            e.get(1)
+                                .and_then(BencodeValue::as_bytes)
+                                .unwrap_or_default()
+                        )
+                    ),
+                    _ => "malformed KRPC error".to_string(),
+                };
+                self.complete(t, from, Err(anyhow::anyhow!(error)));
+            }
+            _ => {}
+        }
+    }
+
+    // Hands a reply to the query waiting for it. Replies from anyone but the
+    // node we asked are ignored.
+    fn complete(&self, t: &[u8], from: SocketAddr, result: anyhow::Result<BencodeValue>) {
+        let Ok(t) = <[u8; 2]>::try_from(t) else {
+            return;
+        };
+        let mut transactions = self.transactions.lock().unwrap();
+        if transactions.get(&t).is_some_and(|tr| tr.address == from) {
+            let transaction = transactions.remove(&t).unwrap();
+            let _ = transaction.reply.send(result);
+        }
+    }
+
+    // The `r` dictionary answering a query, or a KRPC error.
+    fn answer(
+        &self,
+        from: SocketAddr,
+        message: &BencodeValue,
+    ) -> Result<BencodeValue, (i64, &'static str)> {
+        let malformed = (PROTOCOL_ERROR, "malformed query");
+        let args = message.get(b"a").ok_or(malformed)?;
+        let id = response_id(args).ok_or(malformed)?;
+        let hash = |key: &[u8]| {
+            args.get(key)
+                .and_then(BencodeValue::as_bytes)
+                .and_then(NodeId::from_bytes)
+                .ok_or(malformed)
+        };
+        let mut reply = vec![("id", BencodeValue::bytes(self.id.0))];
+        match message
+            .get(b"q")
+            .and_then(BencodeValue::as_bytes)
+            .ok_or(malformed)?
+        {
+            b"ping" => {}
+            b"find_node" => reply.extend(self.closest_nodes(&hash(b"target")?)),
+            b"get_peers" => {
+                let info_hash = hash(b"info_hash")?;
+                self.secrets.lock().unwrap().rotate();
+                let secret = self.secrets.lock().unwrap().current;
+                reply.push(("token", BencodeValue::bytes(token(from.ip(), &secret))));
+                let peers = self.stored_peers(&info_hash.0);
+                if peers.is_empty() {
+                    reply.extend(self.closest_nodes(&info_hash));
+                } else {
+                    let values = peers
+                        .into_iter()
+                        .map(|peer| BencodeValue::bytes(compact([peer]).into_vec()));
+                    reply.push(("values", BencodeValue::List(values.collect())));
+                }
+            }
+            b"announce_peer" => {
+                let info_hash = hash(b"info_hash")?;
+                let given = args
+                    .get(b"token")
+                    .and_then(BencodeValue::as_bytes)
+                    .ok_or(malformed)?;
+                let valid = {
+                    let mut secrets = self.secrets.lock().unwrap();
+                    secrets.rotate();
+                    [secrets.current, secrets.previous]
+                        .iter()
+                        .any(|secret| token(from.ip(), secret) == given)
+                };
+                if !valid {
+                    return Err((PROTOCOL_ERROR, "bad token"));
+                }
+                let implied = args.get(b"implied_port").and_then(BencodeValue::as_int) == Some(1);
+                let port = match implied {
+                    true => from.port(),
+                    false => args
+                        .get(b"port")
+                        .and_then(BencodeValue::as_int)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .filter(|port| *port != 0)
+                        .ok_or(malformed)?,
+                };
+                self.store_peer(info_hash.0, SocketAddr::new(from.ip(), port));
+            }
+            _ => return Err((METHOD_UNKNOWN, "method unknown")),
+        }
+        // Nodes that query us are alive, unless they say they won't answer (BEP 43).
+        if args.get(b"ro").and_then(BencodeValue::as_int) != Some(1) {
+            self.table
+                .lock()
+                .unwrap()
+                .insert(Node { id, address: from });
+        }
+        Ok(BencodeValue::dict(reply))
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> Vec<(&'static str, BencodeValue)> {
+        let nodes = self.table.lock().unwrap().closest(target, K);
+        let (nodes4, nodes6): (Vec<_>, Vec<_>) =
+            nodes.iter().partition(|node| node.address.is_ipv4());
+        let mut fields = vec![("nodes", BencodeValue::bytes(compact_nodes(nodes4)))];
+        if !nodes6.is_empty() {
+            fields.push(("nodes6", BencodeValue::bytes(compact_nodes(nodes6))));
+        }
+        fields
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], peer: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        if stored.len() < MAX_STORED_PEERS || stored.contains_key(&peer) {
+            stored.insert(peer, Instant::now());
+        }
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(stored) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        stored.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        stored.keys().take(MAX_VALUES).copied().collect()
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+// The sender's id in a query's `a` or a reply's `r` dictionary.
+fn response_id(dict: &BencodeValue) -> Option<NodeId> {
+    dict.get(b"id")
+        .and_then(BencodeValue::as_bytes)
+        .and_then(NodeId::from_bytes)
+}
+
+// Reads datagrams until the node is dropped, which aborts this task.
+async fn receive(dht: Weak<Dht>, socket: Arc<UdpSocket>) {
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        // Not yet set up, just after binding.
+        let Some(dht) = dht.upgrade() else {
+            continue;
+        };
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        if let Ok(message) = BencodeValue::decode(&buffer[..len]) {
+            dht.handle(from, message).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Nodes on localhost, each bootstrapped off the first.
+    async fn swarm(size: usize) -> Vec<Arc<Dht>> {
+        let mut nodes = Vec::new();
+        for _ in 0..size {
+            nodes.push(Dht::bind((Ipv4Addr::LOCALHOST, 0).into()).await.unwrap());
+        }
+        let first = vec![nodes[0].local_addr().unwrap().to_string()];
+        for node in &nodes[1..] {
+            node.bootstrap(&first).await.unwrap();
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn nodes_find_each_other() {
+        let nodes = swarm(4).await;
+        // The first node learns about the others from their queries.
+        assert_eq!(nodes[0].node_count(), 3);
+        // The last one to join finds everyone through the first.
+        assert_eq!(nodes[3].node_count(), 3);
+        let address = nodes[1].local_addr().unwrap();
+        assert_eq!(nodes[2].ping(address).await.unwrap(), nodes[1].id());
+    }
+
+    #[tokio::test]
+    async fn finds_peers_announced_by_another_node() {
+        let nodes = swarm(4).await;
+        let info_hash = [7; 20];
+        nodes[1].announce(info_hash, Some(5555)).await;
+        let peers = nodes[3].find_peers(info_hash).await;
+        assert_eq!(peers, [SocketAddr::from((Ipv4Addr::LOCALHOST, 5555))]);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_and_expired_tokens() {
+        let nodes = swarm(2).await;
+        let address = nodes[0].local_addr().unwrap();
+        let info_hash = [7; 20];
+
+        let error = nodes[1]
+            .announce_peer(address, info_hash, Some(5555), b"bogus")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("203"), "{}", error);
+
+        let token = nodes[1]
+            .get_peers(address, info_hash)
+            .await
+            .unwrap()
+            .token
+            .unwrap();
+        let rotate = || {
+            let mut secrets = nodes[0].secrets.lock().unwrap();
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+        };
+        // Still good after one rotation, gone after the next.
+        rotate();
+        nodes[1]
+            .announce_peer(address, info_hash, Some(5555), &token)
+            .await
+            .unwrap();
+        rotate();
+        let error = nodes[1]
+            .announce_peer(address, info_hash, Some(5555), &token)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("203"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_methods_with_an_error() {
+        let nodes = swarm(2).await;
+        let address = nodes[0].local_addr().unwrap();
+        let error = nodes[1]
+            .query(address, "vote", Vec::new())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("204"), "{}", error);
+    }
+}
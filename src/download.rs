@@ -1,183 +1,77 @@
-use crate::peer::Peer;
-use crate::piece::Piece;
-use crate::torrent::{File, Keys, Torrent};
-use crate::tracker::TrackerResponse;
-use crate::BLOCK_MAX;
-use anyhow::Context;
-use futures_util::stream::StreamExt;
-use sha1::{Digest, Sha1};
-use std::collections::BinaryHeap;
-
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
-    let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
-
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5 /* user config */);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
-                    break;
-                }
-            }
-            Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
-            }
-        }
-    }
-    drop(peers);
-    let mut peers = peer_list;
-
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
-        }
-    }
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
-    // TODO
-    assert!(no_peers.is_empty());
-
-    // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
-    // should probably write every piece to disk so that we can also resume downloads, and seed
-    // later on.
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
-        let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
+use crate::torrent::{File, Info};
 
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
+// Windows device names that cannot be used as file names, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// The files of a torrent, in the order their bytes appear in the piece stream.
+pub struct Downloaded {
+    files: Vec<File>,
+    // Sanitized path of each file under `root`, unique even where the
+    // torrent's own paths only differ in characters that get replaced.
+    paths: Vec<PathBuf>,
+    // Directory the files live under, for multi-file torrents.
+    root: Option<String>,
+}
+
+impl Downloaded {
+    pub fn new(info: &Info) -> Self {
+        let files = info.files();
+        let mut taken = HashSet::new();
+        let paths = files
+            .iter()
+            .map(|file| {
+                let components: Vec<String> = file
+                    .path
+                    .iter()
+                    .map(|c| sanitize_path_component(c))
+                    .collect();
+                unique_path(components, &mut taken)
+            })
+            .collect();
+        Self {
+            files,
+            paths,
+            root: info
+                .is_multi_file()
+                .then(|| sanitize_path_component(info.name())),
         }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
+    }
+}
 
-        eprintln!("start receive loop");
-        let mut all_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
+// Renames the last component to `stem (n).ext` until the path clashes with
+// no file or directory already taken. Compared case-insensitively, as on
+// Windows and macOS.
+fn unique_path(mut components: Vec<String>, taken: &mut HashSet<String>) -> PathBuf {
+    let key = |components: &[String]| components.join("/").to_lowercase();
+    if let Some(name) = components.pop() {
+        let (stem, ext) = match name.rfind('.') {
+            Some(dot) if dot > 0 => name.split_at(dot),
+            _ => (name.as_str(), ""),
+        };
+        let mut candidate = name.clone();
+        let mut n = 1;
         loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    eprintln!("participant finished");
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
-                        }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
-                        }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
-                        }
-                    }
-                }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        eprintln!("got piece");
-                        // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participations have either exited or are
-                            // waiting for more work -- in either case, it is okay to drop all the
-                            // participant futures.
-                            break;
-                        }
-                    } else {
-                        eprintln!("got pieces end");
-                        // there are no peers left, so we can't progress!
-                        break;
-                    }
-                }
+            components.push(candidate);
+            if !taken.contains(&key(&components)) {
+                break;
             }
+            components.pop();
+            candidate = format!("{stem} ({n}){ext}");
+            n += 1;
         }
-        drop(participants);
-
-        if bytes_received == piece_size {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
-        }
-
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]");
-        assert_eq!(hash, piece.hash());
-        eprintln!("all_blocks.len=>{}",all_blocks.len());
-        all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
-    }
-    eprintln!("all_pieces.len() = {}", all_pieces.len());
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
-}
-
-pub struct Downloaded {
-    bytes: Vec<u8>, // TODO: maybe Bytes?
-    files: Vec<File>,
+    }
+    for i in 1..=components.len() {
+        taken.insert(key(&components[..i]));
+    }
+    components.into_iter().collect()
 }
 
 impl<'a> IntoIterator for &'a Downloaded {
@@ -189,16 +83,16 @@ impl<'a> IntoIterator for &'a Downloaded {
 }
 
 pub struct DownloadedIter<'d> {
-    downloaded: &'d Downloaded,
-    file_iter: std::slice::Iter<'d, File>,
-    offset: usize,
+    file_iter: std::iter::Zip<std::slice::Iter<'d, File>, std::slice::Iter<'d, PathBuf>>,
+    root: Option<&'d str>,
+    offset: u64,
 }
 
 impl<'d> DownloadedIter<'d> {
     fn new(d: &'d Downloaded) -> Self {
         Self {
-            downloaded: d,
-            file_iter: d.files.iter(),
+            file_iter: d.files.iter().zip(&d.paths),
+            root: d.root.as_deref(),
             offset: 0,
         }
     }
@@ -208,16 +102,23 @@ impl<'d> Iterator for DownloadedIter<'d> {
     type Item = DownloadedFile<'d>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let file = self.file_iter.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
+        let (file, relative_path) = self.file_iter.next()?;
+        let offset = self.offset;
         self.offset += file.length;
-        Some(DownloadedFile { file, bytes })
+        Some(DownloadedFile {
+            file,
+            relative_path,
+            root: self.root,
+            offset,
+        })
     }
 }
 
 pub struct DownloadedFile<'d> {
     file: &'d File,
-    bytes: &'d [u8],
+    relative_path: &'d Path,
+    root: Option<&'d str>,
+    offset: u64,
 }
 
 impl<'d> DownloadedFile<'d> {
@@ -225,7 +126,148 @@ impl<'d> DownloadedFile<'d> {
         &self.file.path
     }
 
-    pub fn bytes(&self) -> &'d [u8] {
-        self.bytes
+    // `path()` made safe to join onto an output directory.
+    pub fn relative_path(&self) -> &'d Path {
+        self.relative_path
+    }
+
+    // Where the file goes for `download -o <output>`: `output` itself for a
+    // single-file torrent, `output/<name>/<path...>` otherwise.
+    pub fn output_path(&self, output: &Path) -> PathBuf {
+        match self.root {
+            Some(root) => output.join(root).join(self.relative_path()),
+            None => output.to_path_buf(),
+        }
+    }
+
+    // Position of the file's first byte in the torrent's concatenated payload.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.length == 0
     }
-}
\ No newline at end of file
+}
+
+pub fn sanitize_path_component(component: &str) -> String {
+    let mut name: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows silently drops trailing dots and spaces.
+    while name.ends_with(['.', ' ']) {
+        name.pop();
+    }
+    if name.is_empty() {
+        return "_".to_string();
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::BencodeValue;
+
+    fn multi_file_info(paths: &[&[&str]]) -> Info {
+        let files = paths
+            .iter()
+            .map(|path| {
+                BencodeValue::Dict(vec![
+                    (b"length".to_vec(), BencodeValue::Int(1)),
+                    (
+                        b"path".to_vec(),
+                        BencodeValue::List(
+                            path.iter()
+                                .map(|c| BencodeValue::Bytes(c.as_bytes().to_vec()))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+        let dict = BencodeValue::Dict(vec![
+            (b"files".to_vec(), BencodeValue::List(files)),
+            (b"name".to_vec(), BencodeValue::Bytes(b"../root".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(16384)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(vec![0; 20])),
+        ]);
+        Info::from_bytes(&dict.encode()).unwrap()
+    }
+
+    fn output_paths(info: &Info) -> Vec<PathBuf> {
+        Downloaded::new(info)
+            .into_iter()
+            .map(|file| file.output_path(Path::new("out")))
+            .collect()
+    }
+
+    #[test]
+    fn sanitizes_components_that_could_escape_the_output() {
+        assert_eq!(sanitize_path_component(".."), "_");
+        assert_eq!(sanitize_path_component("."), "_");
+        assert_eq!(sanitize_path_component(""), "_");
+        assert_eq!(sanitize_path_component("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_path_component("C:\\Windows"), "C__Windows");
+        assert_eq!(sanitize_path_component("a/../b"), "a_.._b");
+        assert_eq!(sanitize_path_component("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn sanitizes_reserved_and_trailing_names() {
+        assert_eq!(sanitize_path_component("CON"), "_CON");
+        assert_eq!(sanitize_path_component("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_path_component("com1.tar.gz"), "_com1.tar.gz");
+        assert_eq!(sanitize_path_component("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize_path_component("name. "), "name");
+    }
+
+    #[test]
+    fn keeps_every_file_under_the_output_directory() {
+        let info = multi_file_info(&[&["..", "..", "evil"], &["/abs"], &["a/b"]]);
+        assert_eq!(
+            output_paths(&info),
+            [
+                Path::new("out/.._root/_/_/evil"),
+                Path::new("out/.._root/_abs"),
+                Path::new("out/.._root/a_b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gives_files_with_colliding_sanitized_names_their_own_paths() {
+        let info = multi_file_info(&[
+            &["a?"],
+            &["a*"],
+            &["A|"],
+            &["d?", "x.txt"],
+            &["d*", "x.txt"],
+            &["d_"],
+        ]);
+        assert_eq!(
+            output_paths(&info),
+            [
+                Path::new("out/.._root/a_"),
+                Path::new("out/.._root/a_ (1)"),
+                Path::new("out/.._root/A_ (2)"),
+                Path::new("out/.._root/d_/x.txt"),
+                Path::new("out/.._root/d_/x (1).txt"),
+                Path::new("out/.._root/d_ (1)"),
+            ]
+        );
+    }
+}
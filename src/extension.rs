@@ -1,49 +1,261 @@
 #![allow(dead_code)]
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::mem;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-#[derive(Serialize, Deserialize)]
+use crate::decode::value_span;
+
+pub const UT_METADATA: &str = "ut_metadata";
+const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+// The BEP 10 handshake, sent as extension message 0.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ExtensionHeader {
-    pub m: ExtensionMetadata,
-    p: Option<u16>, // port
-    metadata_size: u32,
+    // Extension name to the id the sender wants to receive its messages on;
+    // 0 means the sender turned the extension off.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // The sender's listen port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    // Client name and version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    // Our address as the sender sees it, 4 or 16 bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    // How many outstanding requests the sender queues.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    // Only sent by peers that have the metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ExtensionMetadata {
-    pub ut_metadata: u8,
-    ut_pex: Option<u8>,
+impl ExtensionHeader {
+    // The id the sender of this handshake wants `name` messages on.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+    }
+
+    pub fn client(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let ip = self.yourip.as_ref()?.as_slice();
+        match ip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+            _ => None,
+        }
+    }
 }
 
-impl ExtensionHeader {
+// What to do with an extension message once its handler has looked at it.
+pub enum Dispatch {
+    // Send this payload back on the peer's id for the same extension.
+    Reply(Vec<u8>),
+    Done,
+    // Queue the message for `Peer::recv`, e.g. for whoever awaits a reply.
+    Deliver,
+}
+
+// An extension we support, registered under its BEP 10 name. `handle` runs on
+// the peer's reader task, so it must not block.
+pub trait ExtensionHandler: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Adds the extension's own fields to our handshake, e.g. `metadata_size`.
+    fn extend_handshake(&self, _header: &mut ExtensionHeader) {}
+
+    // A message the peer sent on our id for this extension.
+    fn handle(&self, from: SocketAddr, payload: &[u8]) -> anyhow::Result<Dispatch>;
+}
+
+// The extensions we offer a peer. They get ids 1, 2, ... in registration order.
+#[derive(Default, Clone)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Arc<dyn ExtensionHandler>>,
+    // Where our listener accepts peers, if we have one.
+    listen_port: Option<u16>,
+}
+
+impl ExtensionRegistry {
     pub fn new() -> Self {
-        let metadata = ExtensionMetadata {
-            ut_metadata: 1,
-            ut_pex: Some(2),
-        };
-        let port = Some(6881);
-        let size = (mem::size_of_val(&metadata) + mem::size_of_val(&port)) as u32;
+        Self::default()
+    }
 
-        Self {
-            m: metadata,
-            p: port,
-            metadata_size: size,
+    pub fn with_handler(mut self, handler: impl ExtensionHandler + 'static) -> Self {
+        assert!(
+            self.handlers.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    // Our id for the extension called `name`.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        let index = self.handlers.iter().position(|h| h.name() == name)?;
+        Some(index as u8 + 1)
+    }
+
+    pub fn handler(&self, id: u8) -> Option<&Arc<dyn ExtensionHandler>> {
+        self.handlers.get((id as usize).checked_sub(1)?)
+    }
+
+    // Our handshake for a peer at `address`, offering to queue `reqq` requests.
+    pub fn handshake(&self, address: SocketAddr, reqq: usize) -> ExtensionHeader {
+        let mut header = ExtensionHeader {
+            p: self.listen_port,
+            v: Some(ByteBuf::from(CLIENT_VERSION)),
+            reqq: Some(reqq as u32),
+            yourip: Some(ByteBuf::from(match address.ip().to_canonical() {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            })),
+            ..Default::default()
+        };
+        for (index, handler) in self.handlers.iter().enumerate() {
+            header
+                .m
+                .insert(handler.name().to_string(), index as i64 + 1);
+            handler.extend_handshake(&mut header);
         }
+        header
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ExtensionMessage {
     pub msg_type: ExtensionMessageType,
-    pub piece: u8,
+    pub piece: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub total_size: Option<u32>,
 }
 
+impl ExtensionMessage {
+    pub fn request(piece: u32) -> Self {
+        Self {
+            msg_type: ExtensionMessageType::Request,
+            piece,
+            total_size: None,
+        }
+    }
+
+    // Followed by the piece data in the same payload.
+    pub fn data(piece: u32, total_size: u32) -> Self {
+        Self {
+            msg_type: ExtensionMessageType::Data,
+            piece,
+            total_size: Some(total_size),
+        }
+    }
+
+    pub fn reject(piece: u32) -> Self {
+        Self {
+            msg_type: ExtensionMessageType::Reject,
+            piece,
+            total_size: None,
+        }
+    }
+
+    // Splits a ut_metadata payload into the message and the piece data that
+    // follows the dictionary in a Data message.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let end = value_span(payload, &[])?.end;
+        let message = serde_bencode::from_bytes(&payload[..end])?;
+        Ok((message, &payload[end..]))
+    }
+}
+
 #[derive(Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum ExtensionMessageType {
     Request,
     Data,
     Reject,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    impl ExtensionHandler for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn handle(&self, _from: SocketAddr, _payload: &[u8]) -> anyhow::Result<Dispatch> {
+            Ok(Dispatch::Done)
+        }
+    }
+
+    fn registry() -> ExtensionRegistry {
+        ExtensionRegistry::new()
+            .with_handler(Named(UT_METADATA))
+            .with_handler(Named("ut_pex"))
+    }
+
+    #[test]
+    fn numbers_extensions_in_registration_order() {
+        let registry = registry();
+        assert_eq!(registry.id(UT_METADATA), Some(1));
+        assert_eq!(registry.id("ut_pex"), Some(2));
+        assert_eq!(registry.id("lt_donthave"), None);
+        assert_eq!(registry.handler(2).unwrap().name(), "ut_pex");
+        assert!(registry.handler(0).is_none());
+        assert!(registry.handler(3).is_none());
+    }
+
+    #[test]
+    fn offers_our_extensions_port_and_queue_depth() {
+        let registry = registry().with_listen_port(51413);
+        let header = registry.handshake("[::ffff:203.0.113.7]:6881".parse().unwrap(), 250);
+        let bytes = serde_bencode::to_bytes(&header).unwrap();
+        let header: ExtensionHeader = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(header.id(UT_METADATA), Some(1));
+        assert_eq!(header.id("ut_pex"), Some(2));
+        assert_eq!(header.p, Some(51413));
+        assert_eq!(header.reqq, Some(250));
+        assert_eq!(header.client().as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(header.yourip(), Some("203.0.113.7".parse().unwrap()));
+
+        let header = ExtensionRegistry::new().handshake("10.0.0.1:1".parse().unwrap(), 5);
+        assert_eq!(header.p, None);
+        assert!(header.m.is_empty());
+    }
+
+    #[test]
+    fn reads_the_ids_a_peer_wants() {
+        let bytes = b"d1:md5:bogusi300e8:disabledi0e8:negativei-1e11:ut_metadatai3ee1:pi6881e4:reqqi500e1:xi1ee";
+        let header: ExtensionHeader = serde_bencode::from_bytes(bytes).unwrap();
+        assert_eq!(header.id(UT_METADATA), Some(3));
+        assert_eq!(header.id("disabled"), None);
+        assert_eq!(header.id("bogus"), None);
+        assert_eq!(header.id("negative"), None);
+        assert_eq!(header.id("ut_pex"), None);
+        assert_eq!((header.p, header.reqq), (Some(6881), Some(500)));
+
+        let header: ExtensionHeader = serde_bencode::from_bytes(b"de").unwrap();
+        assert!(header.m.is_empty() && header.metadata_size.is_none());
+    }
+}
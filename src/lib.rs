@@ -1,6 +1,22 @@
+pub mod choker;
+pub mod create;
 pub mod decode;
+pub mod dht;
+pub mod download;
+pub mod extension;
 pub mod magnet;
+pub mod message;
+pub mod metadata;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod piece;
+pub mod pipeline;
+pub mod rate;
+pub mod resume;
+pub mod seed;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
-pub mod extension;
\ No newline at end of file
+pub mod udp_tracker;
+pub mod verify;
@@ -1,22 +1,24 @@
-use anyhow::Context;
-use rand::seq::SliceRandom;
-use sha1::{Digest, Sha1};
-use std::{collections::HashMap, net::SocketAddr};
-use tokio::task::JoinSet;
-use url::{form_urlencoded, Url};
+use futures_util::future::join_all;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use url::Url;
 
 use crate::{
+    dht::Dht,
+    extension::ExtensionRegistry,
+    metadata::{fetch_metadata, MetadataExtension},
     peer::Peer,
-    torrent::Info,
-    tracker::{TrackerRequest, TrackerResponse},
+    torrent::Torrent,
+    tracker::{self, TrackerRequest},
 };
 
-const MAGNET_XT_PREFIX: &'static str = "urn:btih:";
+const MAGNET_XT_PREFIX: &str = "urn:btih:";
 
 pub struct Magnet {
     pub info_hash: [u8; 20], // raw bytes
     pub file_name: Option<String>,
     pub tracker_url: Option<Url>,
+    // Where peers come from besides the tracker, and instead of it without one.
+    pub dht: Option<Arc<Dht>>,
 }
 
 impl Magnet {
@@ -41,27 +43,39 @@ impl Magnet {
             info_hash,
             file_name,
             tracker_url,
+            dht: None,
         };
         Ok(magnet)
     }
 
+    pub fn with_dht(mut self, dht: Arc<Dht>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
     pub async fn get_peer_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
-        let request = TrackerRequest::new(1);
-        let params = serde_urlencoded::to_string(&request)?;
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash).collect();
-        let url = format!(
-            "{}?{}&info_hash={}",
-            self.tracker_url.as_ref().unwrap(),
-            params,
-            info_hash_str,
+        anyhow::ensure!(
+            self.tracker_url.is_some() || self.dht.is_some(),
+            "magnet link has no tracker, and the DHT is off"
         );
-
-        let response = reqwest::get(url).await?;
-        let tracker_response =
-            serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
-        let peer_addrs = tracker_response.peers();
-        println!("Found peers: {:?}", peer_addrs);
-        Ok(peer_addrs)
+        let mut peers = Vec::new();
+        if let Some(url) = &self.tracker_url {
+            let request = TrackerRequest::new(1);
+            match tracker::announce(url.as_str(), self.info_hash, &request).await {
+                Ok(response) => peers = response.peers,
+                Err(e) if self.dht.is_some() => eprintln!("Announce to {} failed: {}", url, e),
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(dht) = &self.dht {
+            for peer in dht.find_peers(self.info_hash).await {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        println!("Found peers: {:?}", peers);
+        Ok(peers)
     }
 
     pub async fn handshake(&self) -> anyhow::Result<Peer> {
@@ -70,7 +84,8 @@ impl Magnet {
             match Peer::new(peer_address, self.info_hash).await {
                 Ok(mut peer) => {
                     if peer.supports_extension {
-                        peer.get_pieces().await?;
+                        peer.initial_pieces().await?;
+                        peer.set_extensions(self.extensions());
                         peer.extension_handshake().await?;
                     }
                     return Ok(peer);
@@ -87,17 +102,14 @@ impl Magnet {
         for peer_address in peer_addrs {
             match Peer::new(peer_address, self.info_hash).await {
                 Ok(mut peer) => {
-                    let pieces = peer.get_pieces().await?;
+                    let (pieces, _) = peer.initial_pieces().await?;
                     if pieces.contains(&piece) && peer.supports_extension {
+                        peer.set_extensions(self.extensions());
                         peer.extension_handshake().await?;
                         let metadata = peer.extension_metadata().await?;
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            metadata.piece_length,                               // piece_len
-                            metadata.file_len() - piece * metadata.piece_length, // last piece
-                        );
+                        let piece_len = metadata.piece_len(piece);
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
@@ -107,99 +119,42 @@ impl Magnet {
         Err(anyhow::anyhow!("Could not find peer"))
     }
 
-    pub async fn download(&self) -> anyhow::Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
-        let mut metadata: Option<Info> = None;
-        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
-        let mut join_set = JoinSet::new();
-
-        for peer_address in peer_addrs {
-            match Peer::new(peer_address, self.info_hash).await {
-                Ok(mut peer) => {
-                    if peer.supports_extension {
-                        let pieces = peer.get_pieces().await?;
-                        peer.extension_handshake().await?;
-                        if metadata.is_none() {
-                            metadata = Some(peer.extension_metadata().await?);
-                        }
-                        for piece in pieces {
-                            peer_piece_map
-                                .entry(piece)
-                                .or_insert_with(Vec::new)
-                                .push(peer.clone());
-                        }
-                        peer.prepare_download().await?;
-                    }
-                }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
-            }
-        }
-
-        if peer_piece_map.is_empty() || metadata.is_none() {
-            return Err(anyhow::anyhow!("Could not connect to any peers"));
-        }
-
-        let metadata = metadata.unwrap();
-        let piece_hashes = metadata.pieces();
-        let num_pieces = piece_hashes.len();
-        let piece_len = metadata.piece_length;
-        let file_len = metadata.file_len();
-
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
-        };
-
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
-            let piece_hashes = piece_hashes.clone();
-            let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
-
-            join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
-                    Ok(data) => {
-                        println!(
-                            "Downloaded piece {}/{} from peer {}",
-                            piece_number, num_pieces, peer.address
-                        );
-                        if piece_hashes[piece] != *Sha1::digest(&data) {
-                            eprintln!(
-                                "Piece {}/{} failed verification. Will retry...",
-                                piece_number, num_pieces
-                            );
-                            (piece, vec![])
-                        } else {
-                            (piece, data)
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!(
-                            "Error loading piece {}/{}: {}. Will retry...",
-                            piece_number, num_pieces, e
-                        );
-                        (piece, vec![])
-                    }
-                }
-            });
-        };
+    // Until we have the metadata, peers asking us for it get rejected.
+    fn extensions(&self) -> Arc<ExtensionRegistry> {
+        Arc::new(ExtensionRegistry::new().with_handler(MetadataExtension::new(None)))
+    }
 
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
-        }
+    // Fetches the info dictionary from every peer that offers it.
+    pub async fn torrent(&self) -> anyhow::Result<Torrent> {
+        let metadata = fetch_metadata(self.metadata_peers().await?, self.info_hash).await?;
+        Torrent::from_magnet_and_metadata(self, metadata)
+    }
 
-        let mut file_bytes = vec![0u8; file_len as usize];
-        while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
-            } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
+    // Connects to all peers at once, keeping those that have the metadata.
+    async fn metadata_peers(&self) -> anyhow::Result<Vec<Peer>> {
+        let peer_addrs = self.get_peer_addrs().await?;
+        let connects = peer_addrs.into_iter().map(|address| async move {
+            let result = async {
+                let mut peer = Peer::new(address, self.info_hash).await?;
+                anyhow::ensure!(peer.supports_extension, "peer does not support extensions");
+                peer.initial_pieces().await?;
+                peer.set_extensions(self.extensions());
+                peer.extension_handshake().await?;
+                anyhow::ensure!(
+                    peer.metadata_extension_id.is_some() && peer.metadata_size.is_some(),
+                    "peer does not offer the metadata"
+                );
+                Ok(peer)
+            };
+            (address, result.await)
+        });
+        let mut peers = Vec::new();
+        for (address, result) in join_all(connects).await {
+            match result {
+                Ok(peer) => peers.push(peer),
+                Err(e) => eprintln!("{} -> {}", address, e),
             }
         }
-        Ok(file_bytes)
+        Ok(peers)
     }
-}
\ No newline at end of file
+}
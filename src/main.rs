@@ -1,25 +1,61 @@
+use anyhow::Context;
+use bitvec::prelude::*;
 use clap::{Parser, Subcommand};
-use std::{net::SocketAddr, path::PathBuf};
-use tokio::{fs::File, io::AsyncWriteExt};
+use std::{
+    io::Read,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs::File, io::AsyncWriteExt, net::TcpListener};
 use url::Url;
 
-use bittorrent_starter_rust::decode::decode_bencoded_value;
+use bittorrent_starter_rust::create::{create_torrent, CreateOptions};
+use bittorrent_starter_rust::decode::{decode_bencoded_value, BinaryEncoding};
+use bittorrent_starter_rust::dht::{Dht, DEFAULT_BOOTSTRAP, DHT_INTERVAL, DHT_PORT};
 use bittorrent_starter_rust::magnet::Magnet;
 use bittorrent_starter_rust::peer::Peer;
-use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::pipeline::{MAX_REQUESTS, MIN_REQUESTS};
+use bittorrent_starter_rust::resume::{resume_path, Resume};
+use bittorrent_starter_rust::seed::{Seeder, UPLOAD_SLOTS};
+use bittorrent_starter_rust::storage::FileStorage;
+use bittorrent_starter_rust::torrent::{DownloadOptions, Torrent};
+use bittorrent_starter_rust::tracker::{next_announce, TrackerRequest, TrackerSession, Transfer};
+use bittorrent_starter_rust::verify::{verify, PieceStatus};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+    #[command(flatten)]
+    dht: DhtArgs,
+}
+
+#[derive(clap::Args)]
+struct DhtArgs {
+    /// Find peers on the mainline DHT as well; always on without a tracker
+    #[arg(long, global = true)]
+    dht: bool,
+    /// UDP port of our DHT node
+    #[arg(long = "dht-port", global = true, default_value_t = DHT_PORT)]
+    dht_port: u16,
+    /// host:port of a node to join the DHT through; may be repeated
+    #[arg(long = "dht-bootstrap", global = true, default_values_t = DEFAULT_BOOTSTRAP.map(String::from))]
+    dht_bootstrap: Vec<String>,
 }
 
 #[derive(Subcommand)]
 #[clap(rename_all = "snake_case")]
 enum Command {
     Decode {
-        value: String,
+        /// Bencoded value; read from stdin when omitted or "-"
+        value: Option<String>,
+        #[arg(short, long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        binary: BinaryEncoding,
     },
     Info {
         torrent: PathBuf,
@@ -29,7 +65,8 @@ enum Command {
     },
     Handshake {
         torrent: PathBuf,
-        peer_address: SocketAddr,
+        /// host:port; a name with several addresses is tried happy-eyeballs style
+        peer_address: String,
     },
     DownloadPiece {
         #[arg(short)]
@@ -41,6 +78,43 @@ enum Command {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
+        #[command(flatten)]
+        options: DownloadArgs,
+    },
+    Verify {
+        torrent: PathBuf,
+        path: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    Seed {
+        torrent: PathBuf,
+        path: PathBuf,
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Peers uploaded to at once, plus one optimistic unchoke
+        #[arg(long = "upload-slots", default_value_t = UPLOAD_SLOTS)]
+        upload_slots: usize,
+    },
+    Create {
+        path: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        #[arg(long = "tracker", required = true)]
+        trackers: Vec<String>,
+        /// Bytes, or "auto" to pick from the total size
+        #[arg(long = "piece-length", default_value = "auto")]
+        piece_length: String,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long = "created-by", default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        #[arg(long)]
+        source: Option<String>,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
     },
     MagnetParse {
         magnet_link: Url,
@@ -61,23 +135,72 @@ enum Command {
         #[arg(short)]
         output: PathBuf,
         magnet_link: Url,
+        #[command(flatten)]
+        options: DownloadArgs,
     },
 }
 
+#[derive(clap::Args)]
+struct DownloadArgs {
+    /// Fewest block requests kept outstanding per peer
+    #[arg(long = "min-requests", default_value_t = MIN_REQUESTS)]
+    min_requests: usize,
+    /// Most block requests kept outstanding per peer
+    #[arg(long = "max-requests", default_value_t = MAX_REQUESTS)]
+    max_requests: usize,
+    /// Download pieces in order instead of rarest-first
+    #[arg(long)]
+    sequential: bool,
+    /// Peers uploaded to at once, plus one optimistic unchoke
+    #[arg(long = "upload-slots", default_value_t = UPLOAD_SLOTS)]
+    upload_slots: usize,
+    /// Port to accept peers on; another is used if it is taken
+    #[arg(long, default_value_t = 6881)]
+    port: u16,
+}
+
+impl From<DownloadArgs> for DownloadOptions {
+    fn from(args: DownloadArgs) -> Self {
+        Self {
+            min_requests: args.min_requests,
+            max_requests: args.max_requests,
+            sequential: args.sequential,
+            upload_slots: args.upload_slots,
+            dht: None,
+            listener: None,
+        }
+    }
+}
+
 #[tokio::main(worker_threads = 5)]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Command::Decode { value } => {
-            let decoded = decode_bencoded_value(&value)?;
+        Command::Decode {
+            value,
+            file,
+            binary,
+        } => {
+            let encoded = match (value, file) {
+                (_, Some(file)) => std::fs::read(file)?,
+                (Some(value), None) if value != "-" => value.into_bytes(),
+                _ => {
+                    let mut buf = Vec::new();
+                    std::io::stdin().read_to_end(&mut buf)?;
+                    buf
+                }
+            };
+            let decoded = decode_bencoded_value(&encoded, binary)?;
             println!("{}", decoded);
         }
         Command::Info { torrent } => {
             let torrent = Torrent::new(torrent)?;
-            println!("Tracker URL: {}", torrent.announce);
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
             println!("Length: {}", torrent.len());
-            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
             for piece_hash in torrent.pieces() {
@@ -95,7 +218,7 @@ async fn main() -> anyhow::Result<()> {
             peer_address,
         } => {
             let peer = handshake(torrent, peer_address).await?;
-            println!("Peer ID: {}", hex::encode(&peer.id));
+            println!("Peer ID: {}", hex::encode(peer.id));
         }
         Command::DownloadPiece {
             output,
@@ -107,34 +230,118 @@ async fn main() -> anyhow::Result<()> {
             let mut file = File::create(output).await?;
             file.write_all(&piece_bytes).await?;
         }
-        Command::Download { output, torrent } => {
+        Command::Download {
+            output,
+            torrent,
+            options,
+        } => {
             let torrent = Torrent::new(torrent)?;
-            let file_bytes = torrent.download().await?;
-            let mut file = File::create(output).await?;
-            file.write_all(&file_bytes).await?;
+            let port = options.port;
+            let mut options = DownloadOptions::from(options);
+            if args.dht.dht || torrent.announce.is_none() {
+                options.dht = Some(start_dht(&args.dht).await?);
+            }
+            download(torrent, output, options, port).await?;
+        }
+        Command::Verify {
+            torrent,
+            path,
+            json,
+        } => {
+            let torrent = Torrent::new(torrent)?;
+            let report = verify(&torrent.info, &path)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for (piece, status) in report.pieces.iter().enumerate() {
+                    if *status != PieceStatus::Good {
+                        println!("Piece {}: {:?}", piece, status);
+                    }
+                }
+                for file in &report.files {
+                    println!(
+                        "{}: {} good, {} bad, {} missing",
+                        file.path, file.good, file.bad, file.missing
+                    );
+                }
+            }
+            anyhow::ensure!(
+                report.is_ok(),
+                "verification failed: {} bad, {} missing of {} pieces",
+                report.count(PieceStatus::Bad),
+                report.count(PieceStatus::Missing),
+                report.pieces.len()
+            );
+        }
+        Command::Seed {
+            torrent,
+            path,
+            port,
+            upload_slots,
+        } => {
+            let torrent = Torrent::new(torrent)?;
+            let dht = match args.dht.dht || torrent.announce.is_none() {
+                true => Some(start_dht(&args.dht).await?),
+                false => None,
+            };
+            seed(torrent, path, port, upload_slots, dht).await?;
+        }
+        Command::Create {
+            path,
+            output,
+            trackers,
+            piece_length,
+            private,
+            comment,
+            created_by,
+            source,
+            web_seeds,
+        } => {
+            let piece_length = match piece_length.as_str() {
+                "auto" => None,
+                n => Some(
+                    n.parse()
+                        .context("piece length must be \"auto\" or a number")?,
+                ),
+            };
+            let options = CreateOptions {
+                trackers,
+                piece_length,
+                private,
+                comment,
+                created_by: Some(created_by),
+                creation_date: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64),
+                source,
+                web_seeds,
+            };
+            std::fs::write(&output, create_torrent(&path, &options)?)?;
+            let torrent = Torrent::new(output)?;
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
         }
         Command::MagnetParse { magnet_link } => {
             let magnet = Magnet::new(magnet_link)?;
-            println!("Tracker URL: {}", magnet.tracker_url.unwrap());
+            if let Some(tracker_url) = &magnet.tracker_url {
+                println!("Tracker URL: {}", tracker_url);
+            }
             println!("Info Hash: {}", hex::encode(magnet.info_hash));
         }
         Command::MagnetHandshake { magnet_link } => {
-            let magnet = Magnet::new(magnet_link)?;
+            let magnet = magnet(magnet_link, &args.dht).await?;
             let peer = magnet.handshake().await?;
-            println!("Peer ID: {}", hex::encode(&peer.id));
+            println!("Peer ID: {}", hex::encode(peer.id));
             println!(
                 "Peer Metadata Extension ID: {}",
                 peer.metadata_extension_id.unwrap()
             );
         }
         Command::MagnetInfo { magnet_link } => {
-            let magnet = Magnet::new(magnet_link)?;
-            let mut peer = magnet.handshake().await?;
-            let metadata = peer.extension_metadata().await?;
-            let torrent = Torrent::from_magnet_and_metadata(magnet, metadata)?;
-            println!("Tracker URL: {}", torrent.announce);
+            let magnet = magnet(magnet_link, &args.dht).await?;
+            let torrent = magnet.torrent().await?;
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
             println!("Length: {}", torrent.len());
-            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
             for piece_hash in torrent.pieces() {
@@ -146,7 +353,7 @@ async fn main() -> anyhow::Result<()> {
             magnet_link,
             piece,
         } => {
-            let magnet = Magnet::new(magnet_link)?;
+            let magnet = magnet(magnet_link, &args.dht).await?;
             let piece_bytes = magnet.download_piece(piece).await?;
             let mut file = File::create(output).await?;
             file.write_all(&piece_bytes).await?;
@@ -154,25 +361,176 @@ async fn main() -> anyhow::Result<()> {
         Command::MagnetDownload {
             output,
             magnet_link,
+            options,
         } => {
-            let magnet = Magnet::new(magnet_link)?;
-            let file_bytes = magnet.download().await?;
-            let mut file = File::create(output).await?;
-            file.write_all(&file_bytes).await?;
+            let magnet = magnet(magnet_link, &args.dht).await?;
+            let torrent = magnet.torrent().await?;
+            let port = options.port;
+            let mut options = DownloadOptions::from(options);
+            options.dht = magnet.dht.clone();
+            download(torrent, output, options, port).await?;
         }
     }
 
     Ok(())
 }
 
+// Joins the DHT. Without any node answering ours still serves those that find
+// it later, so that is no error.
+async fn start_dht(args: &DhtArgs) -> anyhow::Result<Arc<Dht>> {
+    // Another client may have the port; any other will do.
+    let dht = match Dht::bind((Ipv4Addr::UNSPECIFIED, args.dht_port).into()).await {
+        Ok(dht) => dht,
+        Err(_) => Dht::bind((Ipv4Addr::UNSPECIFIED, 0).into()).await?,
+    };
+    match dht.bootstrap(&args.dht_bootstrap).await {
+        Ok(count) => println!("DHT node on {} knows {} nodes", dht.local_addr()?, count),
+        Err(e) => eprintln!("DHT bootstrap failed: {}", e),
+    }
+    Ok(dht)
+}
+
+// Magnet links without a tracker can only find peers on the DHT.
+async fn magnet(magnet_link: Url, args: &DhtArgs) -> anyhow::Result<Magnet> {
+    let magnet = Magnet::new(magnet_link)?;
+    if args.dht || magnet.tracker_url.is_none() {
+        return Ok(magnet.with_dht(start_dht(args).await?));
+    }
+    Ok(magnet)
+}
+
 async fn discover_peers(file_name: PathBuf) -> anyhow::Result<Vec<SocketAddr>> {
     let torrent = Torrent::new(file_name)?;
     let peer_addrs = torrent.get_peer_addrs().await?;
     Ok(peer_addrs)
 }
 
-async fn handshake(file_name: PathBuf, peer_address: SocketAddr) -> anyhow::Result<Peer> {
+async fn handshake(file_name: PathBuf, peer_address: String) -> anyhow::Result<Peer> {
     let torrent = Torrent::new(file_name)?;
-    let peer = Peer::new(peer_address, torrent.info_hash()?).await?;
+    let addresses: Vec<_> = tokio::net::lookup_host(&peer_address).await?.collect();
+    let peer = Peer::connect(&addresses, torrent.info_hash()).await?;
     Ok(peer)
-}
\ No newline at end of file
+}
+
+// [::] takes IPv4 connections as well on most systems; fall back where IPv6 is off.
+async fn listen(port: u16) -> std::io::Result<TcpListener> {
+    match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(listener) => Ok(listener),
+        Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await,
+    }
+}
+
+async fn download(
+    torrent: Torrent,
+    output: PathBuf,
+    mut options: DownloadOptions,
+    port: u16,
+) -> anyhow::Result<()> {
+    // Another client may have the port; the tracker is told whichever we got.
+    let listener = match listen(port).await {
+        Ok(listener) => listener,
+        Err(_) => listen(0).await?,
+    };
+    options.listener = Some(Arc::new(listener));
+    let storage = Arc::new(FileStorage::new(&output, &torrent.info)?);
+    let mut resume = Resume::load(
+        resume_path(&output, &torrent.info),
+        &torrent.info,
+        &*storage,
+    )?;
+    let summary = torrent.download(storage, &mut resume, &options).await?;
+    println!(
+        "Downloaded {} pieces ({} bytes) to {}, {} already on disk",
+        summary.pieces,
+        summary.bytes,
+        output.display(),
+        summary.resumed
+    );
+    if !summary.endgame_time.is_zero() {
+        println!(
+            "Endgame took {:.1?}, {} duplicate bytes",
+            summary.endgame_time, summary.duplicate_bytes
+        );
+    }
+    Ok(())
+}
+
+// Serves the verified pieces of the data at `path` until interrupted.
+async fn seed(
+    torrent: Torrent,
+    path: PathBuf,
+    port: u16,
+    upload_slots: usize,
+    dht: Option<Arc<Dht>>,
+) -> anyhow::Result<()> {
+    let report = verify(&torrent.info, &path)?;
+    let have: BitVec<u8, Msb0> = report
+        .pieces
+        .iter()
+        .map(|status| *status == PieceStatus::Good)
+        .collect();
+    anyhow::ensure!(
+        have.any(),
+        "no verified pieces of {} at {}",
+        torrent.info.name(),
+        path.display()
+    );
+    let left: u64 = have
+        .iter_zeros()
+        .map(|piece| torrent.info.piece_len(piece) as u64)
+        .sum();
+
+    let storage = Arc::new(FileStorage::open(&path, &torrent.info)?);
+    let listener = listen(port).await?;
+    let port = listener.local_addr()?.port();
+    println!(
+        "Seeding {}/{} pieces of {} on port {}",
+        have.count_ones(),
+        have.len(),
+        torrent.info.name(),
+        port
+    );
+
+    let info_hash = torrent.info_hash();
+    let request = TrackerRequest::new(left).with_port(port);
+    let mut tracker = torrent
+        .announce
+        .map(|url| TrackerSession::new(url, info_hash, request));
+    // BEP 27 keeps private torrents off the DHT.
+    let dht = dht.filter(|_| !torrent.info.is_private());
+    let mut dht_tick = tokio::time::interval(DHT_INTERVAL);
+    let mut seeder = Seeder::new(upload_slots).with_listen_port(port);
+    seeder.add(torrent.info, storage, have);
+    let seeder = Arc::new(seeder);
+    let transfer = || Transfer {
+        uploaded: seeder.uploaded(&info_hash),
+        downloaded: 0,
+        left,
+    };
+
+    let run = seeder.clone().run(listener);
+    tokio::pin!(run);
+    let result = loop {
+        tokio::select! {
+            result = &mut run => break result,
+            _ = next_announce(tracker.as_ref()) => {
+                let Some(tracker) = &mut tracker else { continue };
+                if let Err(e) = tracker.announce(transfer()).await {
+                    eprintln!("Announce to {} failed: {}", tracker.url(), e);
+                }
+            }
+            _ = dht_tick.tick(), if dht.is_some() => {
+                // Lookups take a while; serving goes on meanwhile.
+                let dht = dht.clone().unwrap();
+                tokio::spawn(async move { dht.announce(info_hash, Some(port)).await });
+            }
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+    };
+    if let Some(tracker) = &mut tracker {
+        if let Err(e) = tracker.stopped(transfer()).await {
+            eprintln!("Announce to {} failed: {}", tracker.url(), e);
+        }
+    }
+    result
+}
@@ -0,0 +1,326 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Large enough for a bitfield of a few million pieces or a 128 KiB block,
+// small enough that a hostile length prefix can't make us allocate gigabytes.
+const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    Piece {
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    Cancel(BlockRequest),
+    Port(u16),
+    // BEP 10; `id` 0 is the extension handshake.
+    Extension {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    // Ids we don't implement. Kept rather than rejected so the connection can
+    // carry on, as BEP 3 asks of clients.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    const CHOKE: u8 = 0;
+    const UNCHOKE: u8 = 1;
+    const INTERESTED: u8 = 2;
+    const NOT_INTERESTED: u8 = 3;
+    const HAVE: u8 = 4;
+    const BITFIELD: u8 = 5;
+    const REQUEST: u8 = 6;
+    const PIECE: u8 = 7;
+    const CANCEL: u8 = 8;
+    const PORT: u8 = 9;
+    const EXTENSION: u8 = 20;
+
+    fn id(&self) -> Option<u8> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => Self::CHOKE,
+            Message::Unchoke => Self::UNCHOKE,
+            Message::Interested => Self::INTERESTED,
+            Message::NotInterested => Self::NOT_INTERESTED,
+            Message::Have(_) => Self::HAVE,
+            Message::Bitfield(_) => Self::BITFIELD,
+            Message::Request(_) => Self::REQUEST,
+            Message::Piece { .. } => Self::PIECE,
+            Message::Cancel(_) => Self::CANCEL,
+            Message::Port(_) => Self::PORT,
+            Message::Extension { .. } => Self::EXTENSION,
+            Message::Unknown { id, .. } => *id,
+        })
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => 0,
+            Message::Have(_) => 4,
+            Message::Bitfield(bits) => bits.len(),
+            Message::Request(_) | Message::Cancel(_) => 12,
+            Message::Piece { data, .. } => 8 + data.len(),
+            Message::Port(_) => 2,
+            Message::Extension { payload, .. } => 1 + payload.len(),
+            Message::Unknown { payload, .. } => payload.len(),
+        }
+    }
+
+    // Parses the bytes after the length prefix, i.e. the id and its payload.
+    fn from_frame(mut frame: BytesMut) -> anyhow::Result<Self> {
+        let id = frame.get_u8();
+        let expect_len = |frame: &BytesMut, len: usize| {
+            anyhow::ensure!(
+                frame.len() == len,
+                "message {} has a {} byte payload, expected {}",
+                id,
+                frame.len(),
+                len
+            );
+            Ok(())
+        };
+        let message = match id {
+            Self::CHOKE => expect_len(&frame, 0).map(|_| Message::Choke)?,
+            Self::UNCHOKE => expect_len(&frame, 0).map(|_| Message::Unchoke)?,
+            Self::INTERESTED => expect_len(&frame, 0).map(|_| Message::Interested)?,
+            Self::NOT_INTERESTED => expect_len(&frame, 0).map(|_| Message::NotInterested)?,
+            Self::HAVE => {
+                expect_len(&frame, 4)?;
+                Message::Have(frame.get_u32())
+            }
+            Self::BITFIELD => Message::Bitfield(frame.to_vec()),
+            Self::REQUEST | Self::CANCEL => {
+                expect_len(&frame, 12)?;
+                let request = BlockRequest {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    length: frame.get_u32(),
+                };
+                if id == Self::REQUEST {
+                    Message::Request(request)
+                } else {
+                    Message::Cancel(request)
+                }
+            }
+            Self::PIECE => {
+                anyhow::ensure!(frame.len() >= 8, "piece message too short");
+                Message::Piece {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    data: frame.to_vec(),
+                }
+            }
+            Self::PORT => {
+                expect_len(&frame, 2)?;
+                Message::Port(frame.get_u16())
+            }
+            Self::EXTENSION => {
+                anyhow::ensure!(!frame.is_empty(), "extension message without an id");
+                Message::Extension {
+                    id: frame.get_u8(),
+                    payload: frame.to_vec(),
+                }
+            }
+            id => Message::Unknown {
+                id,
+                payload: frame.to_vec(),
+            },
+        };
+        Ok(message)
+    }
+}
+
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        anyhow::ensure!(
+            length <= MAX_MESSAGE_LEN,
+            "message length {} exceeds limit of {}",
+            length,
+            MAX_MESSAGE_LEN
+        );
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        Message::from_frame(src.split_to(length)).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> anyhow::Result<()> {
+        let Some(id) = msg.id() else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+        let length = 1 + msg.payload_len();
+        dst.reserve(4 + length);
+        dst.put_u32(length as u32);
+        dst.put_u8(id);
+        match msg {
+            Message::Have(index) => dst.put_u32(index),
+            Message::Request(request) | Message::Cancel(request) => {
+                dst.put_u32(request.index);
+                dst.put_u32(request.begin);
+                dst.put_u32(request.length);
+            }
+            Message::Piece { index, begin, data } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_slice(&data);
+            }
+            Message::Port(port) => dst.put_u16(port),
+            Message::Extension { id, payload } => {
+                dst.put_u8(id);
+                dst.put_slice(&payload);
+            }
+            Message::Bitfield(payload) | Message::Unknown { payload, .. } => {
+                dst.put_slice(&payload)
+            }
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(message: Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageCodec.encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Option<Message>> {
+        MessageCodec.decode(&mut BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let request = BlockRequest {
+            index: 7,
+            begin: 16384,
+            length: 16384,
+        };
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0xdead_beef),
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Request(request),
+            Message::Piece {
+                index: 3,
+                begin: 32768,
+                data: vec![1, 2, 3],
+            },
+            Message::Cancel(request),
+            Message::Port(6881),
+            Message::Extension {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+            Message::Unknown {
+                id: 13,
+                payload: vec![9; 5],
+            },
+        ];
+        for message in messages {
+            let mut buf = encode(message.clone());
+            assert_eq!(MessageCodec.decode(&mut buf).unwrap(), Some(message));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn encodes_keep_alive_as_a_bare_length() {
+        assert_eq!(&encode(Message::KeepAlive)[..], [0, 0, 0, 0]);
+        assert_eq!(decode(&[0, 0, 0, 0]).unwrap(), Some(Message::KeepAlive));
+    }
+
+    #[test]
+    fn refuses_lengths_over_the_limit() {
+        let length = (MAX_MESSAGE_LEN as u32 + 1).to_be_bytes();
+        assert!(decode(&length).is_err());
+    }
+
+    #[test]
+    fn waits_for_a_payload_split_across_reads() {
+        let bytes = encode(Message::Piece {
+            index: 1,
+            begin: 0,
+            data: vec![7; 100],
+        });
+        let mut buf = BytesMut::new();
+        for chunk in bytes.chunks(30) {
+            assert_eq!(MessageCodec.decode(&mut buf).unwrap(), None);
+            buf.extend_from_slice(chunk);
+        }
+        assert!(matches!(
+            MessageCodec.decode(&mut buf).unwrap(),
+            Some(Message::Piece { index: 1, begin: 0, data }) if data == [7; 100]
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_length_fixed_size_payloads() {
+        let frames: [&[u8]; 7] = [
+            &[0, 0, 0, 2, Message::CHOKE, 0],
+            &[0, 0, 0, 2, Message::INTERESTED, 0],
+            &[0, 0, 0, 4, Message::HAVE, 0, 0, 1],
+            &[0, 0, 0, 6, Message::HAVE, 0, 0, 0, 1, 0],
+            &[0, 0, 0, 9, Message::REQUEST, 0, 0, 0, 1, 0, 0, 0, 0],
+            &[0, 0, 0, 2, Message::PORT, 0],
+            &[0, 0, 0, 5, Message::PIECE, 0, 0, 0, 1],
+        ];
+        for frame in frames {
+            assert!(decode(frame).is_err(), "accepted {:?}", frame);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use bitvec::prelude::*;
+use futures_util::future::join_all;
+use sha1::{Digest, Sha1};
+use std::{net::SocketAddr, sync::Mutex as StdMutex};
+
+use crate::{
+    extension::{
+        Dispatch, ExtensionHandler, ExtensionHeader, ExtensionMessage, ExtensionMessageType,
+        UT_METADATA,
+    },
+    peer::Peer,
+    torrent::Info,
+};
+
+// BEP 9 sends the info dictionary in pieces of this size; only the last is shorter.
+pub const METADATA_PIECE_LEN: u32 = 16 * 1024;
+// Far beyond any real info dictionary; keeps a peer from making us allocate
+// whatever it likes.
+const MAX_METADATA_SIZE: u32 = 64 * 1024 * 1024;
+
+// Piece `piece` of the bencoded info dictionary `metadata`, if there is one.
+fn metadata_piece(metadata: &[u8], piece: u32) -> Option<&[u8]> {
+    let start = piece as usize * METADATA_PIECE_LEN as usize;
+    let end = metadata.len().min(start + METADATA_PIECE_LEN as usize);
+    (start < end).then(|| &metadata[start..end])
+}
+
+// ut_metadata: answers the peer's requests with pieces of `metadata`, or
+// rejects them while we don't have it. Replies to our own requests are left
+// for `Peer::request_metadata_piece`.
+pub struct MetadataExtension {
+    metadata: Option<Vec<u8>>,
+}
+
+impl MetadataExtension {
+    pub fn new(metadata: Option<Vec<u8>>) -> Self {
+        Self { metadata }
+    }
+}
+
+impl ExtensionHandler for MetadataExtension {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, header: &mut ExtensionHeader) {
+        header.metadata_size = self.metadata.as_ref().map(|m| m.len() as u32);
+    }
+
+    fn handle(&self, _from: SocketAddr, payload: &[u8]) -> anyhow::Result<Dispatch> {
+        let (message, _) = ExtensionMessage::parse(payload)?;
+        if !matches!(message.msg_type, ExtensionMessageType::Request) {
+            return Ok(Dispatch::Deliver);
+        }
+        let piece = message.piece;
+        let data = self
+            .metadata
+            .as_deref()
+            .and_then(|metadata| Some((metadata.len(), metadata_piece(metadata, piece)?)));
+        let payload = match data {
+            Some((total_size, data)) => {
+                let reply = ExtensionMessage::data(piece, total_size as u32);
+                let mut payload = serde_bencode::to_bytes(&reply)?;
+                payload.extend_from_slice(data);
+                payload
+            }
+            None => serde_bencode::to_bytes(&ExtensionMessage::reject(piece))?,
+        };
+        Ok(Dispatch::Reply(payload))
+    }
+}
+
+// The info dictionary being assembled from ut_metadata pieces, which several
+// peers may be fetching at once.
+struct MetadataBuffer {
+    size: u32,
+    data: Vec<u8>,
+    received: BitVec<u8, Msb0>,
+    requested: BitVec<u8, Msb0>,
+}
+
+impl MetadataBuffer {
+    fn new(size: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            size > 0 && size <= MAX_METADATA_SIZE,
+            "invalid metadata size {}",
+            size
+        );
+        let num_pieces = size.div_ceil(METADATA_PIECE_LEN) as usize;
+        Ok(Self {
+            size,
+            data: vec![0; size as usize],
+            received: bitvec![u8, Msb0; 0; num_pieces],
+            requested: bitvec![u8, Msb0; 0; num_pieces],
+        })
+    }
+
+    fn piece_len(&self, piece: u32) -> u32 {
+        METADATA_PIECE_LEN.min(self.size - piece * METADATA_PIECE_LEN)
+    }
+
+    // Takes a piece nobody has asked for yet.
+    fn claim(&mut self) -> Option<u32> {
+        let piece = self.requested.first_zero()?;
+        self.requested.set(piece, true);
+        Some(piece as u32)
+    }
+
+    // Makes a claimed piece available again after its request failed.
+    fn release(&mut self, piece: u32) {
+        let piece = piece as usize;
+        if !self.received[piece] {
+            self.requested.set(piece, false);
+        }
+    }
+
+    fn add(&mut self, piece: u32, data: &[u8]) -> anyhow::Result<()> {
+        let expected = self.piece_len(piece) as usize;
+        anyhow::ensure!(
+            data.len() == expected,
+            "metadata piece {} has {} bytes, expected {}",
+            piece,
+            data.len(),
+            expected
+        );
+        let start = (piece * METADATA_PIECE_LEN) as usize;
+        self.data[start..start + expected].copy_from_slice(data);
+        self.received.set(piece as usize, true);
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.all()
+    }
+
+    fn reset(&mut self) {
+        self.received.fill(false);
+        self.requested.fill(false);
+    }
+
+    // The info dictionary, if it is complete and hashes to `info_hash`.
+    fn verified(&self, info_hash: [u8; 20]) -> Option<Info> {
+        if !self.is_complete() || *Sha1::digest(&self.data) != info_hash {
+            return None;
+        }
+        Info::from_bytes(&self.data).ok()
+    }
+}
+
+// Fetches the info dictionary from peers that offered it in their extension
+// handshake. Peers advertising different sizes can't be sending the same
+// dictionary, so they are grouped by size and the groups tried in turn, the
+// largest first, until one yields bytes that hash to `info_hash`.
+pub async fn fetch_metadata(peers: Vec<Peer>, info_hash: [u8; 20]) -> anyhow::Result<Info> {
+    let mut groups: Vec<(u32, Vec<Peer>)> = Vec::new();
+    for peer in peers {
+        let Some(size) = peer.metadata_size else {
+            continue;
+        };
+        match groups
+            .iter_mut()
+            .find(|(group_size, _)| *group_size == size)
+        {
+            Some((_, group)) => group.push(peer),
+            None => groups.push((size, vec![peer])),
+        }
+    }
+    anyhow::ensure!(!groups.is_empty(), "no peer offers the metadata");
+    // Stable, so equally large groups keep the order their first peer came in.
+    groups.sort_by_key(|(_, group)| std::cmp::Reverse(group.len()));
+    for (size, peers) in groups {
+        match fetch_sized(peers, size, info_hash).await {
+            Ok(info) => return Ok(info),
+            Err(e) => eprintln!("Metadata of {} bytes: {}", size, e),
+        }
+    }
+    anyhow::bail!("could not get valid metadata from any peer")
+}
+
+// Fetches metadata of `size` bytes from peers that all advertised it. The
+// pieces are spread over all of them; if the result fails the hash check, each
+// peer is asked for a whole copy in turn to find the ones sending garbage.
+// Peers that reject requests or misbehave are dropped.
+async fn fetch_sized(mut peers: Vec<Peer>, size: u32, info_hash: [u8; 20]) -> anyhow::Result<Info> {
+    let buffer = StdMutex::new(MetadataBuffer::new(size)?);
+    fetch_from(&mut peers, &buffer).await;
+    if let Some(info) = buffer.lock().unwrap().verified(info_hash) {
+        return Ok(info);
+    }
+    while let Some(peer) = peers.pop() {
+        let address = peer.address;
+        buffer.lock().unwrap().reset();
+        fetch_from(&mut vec![peer], &buffer).await;
+        let buffer = buffer.lock().unwrap();
+        if let Some(info) = buffer.verified(info_hash) {
+            return Ok(info);
+        }
+        if buffer.is_complete() {
+            eprintln!("{} -> metadata does not match the info hash", address);
+        }
+    }
+    anyhow::bail!("no peer sent metadata matching the info hash")
+}
+
+// Lets the peers fetch unclaimed pieces until the buffer is complete or no
+// peer is left, dropping those that fail.
+async fn fetch_from(peers: &mut Vec<Peer>, buffer: &StdMutex<MetadataBuffer>) {
+    while !peers.is_empty() && !buffer.lock().unwrap().is_complete() {
+        let results = join_all(peers.iter_mut().map(|peer| fetch_pieces(peer, buffer))).await;
+        let mut results = results.into_iter();
+        peers.retain(|peer| match results.next() {
+            Some(Err(e)) => {
+                eprintln!("{} -> {}", peer.address, e);
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+async fn fetch_pieces(peer: &mut Peer, buffer: &StdMutex<MetadataBuffer>) -> anyhow::Result<()> {
+    loop {
+        let Some(piece) = buffer.lock().unwrap().claim() else {
+            return Ok(());
+        };
+        let result = match peer.request_metadata_piece(piece).await {
+            Ok((message, data)) => match message.msg_type {
+                ExtensionMessageType::Data => {
+                    let mut buffer = buffer.lock().unwrap();
+                    if message.total_size == Some(buffer.size) {
+                        buffer.add(piece, &data)
+                    } else {
+                        Err(anyhow::anyhow!("metadata size changed"))
+                    }
+                }
+                _ => Err(anyhow::anyhow!("peer rejected metadata piece {}", piece)),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            buffer.lock().unwrap().release(piece);
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::BencodeValue;
+
+    // An info dictionary just over one metadata piece long.
+    fn metadata() -> Vec<u8> {
+        BencodeValue::Dict(vec![
+            (b"length".to_vec(), BencodeValue::Int(1000 * 16384)),
+            (b"name".to_vec(), BencodeValue::Bytes(b"m".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(16384)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(vec![7; 1000 * 20])),
+        ])
+        .encode()
+    }
+
+    fn request(extension: &MetadataExtension, piece: u32) -> (ExtensionMessage, Vec<u8>) {
+        let payload = serde_bencode::to_bytes(&ExtensionMessage::request(piece)).unwrap();
+        let from = "127.0.0.1:6881".parse().unwrap();
+        let Dispatch::Reply(reply) = extension.handle(from, &payload).unwrap() else {
+            panic!("request was not answered");
+        };
+        let (message, data) = ExtensionMessage::parse(&reply).unwrap();
+        (message, data.to_vec())
+    }
+
+    #[test]
+    fn answers_requests_with_pieces_of_the_metadata() {
+        let metadata = metadata();
+        let extension = MetadataExtension::new(Some(metadata.clone()));
+
+        let (message, data) = request(&extension, 0);
+        assert!(matches!(message.msg_type, ExtensionMessageType::Data));
+        assert_eq!(message.piece, 0);
+        assert_eq!(message.total_size, Some(metadata.len() as u32));
+        assert_eq!(data, metadata[..16384]);
+
+        let (message, data) = request(&extension, 1);
+        assert!(matches!(message.msg_type, ExtensionMessageType::Data));
+        assert_eq!(data, metadata[16384..]);
+
+        let (message, data) = request(&extension, 2);
+        assert!(matches!(message.msg_type, ExtensionMessageType::Reject));
+        assert_eq!(message.piece, 2);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn rejects_requests_without_metadata_and_delivers_replies() {
+        let extension = MetadataExtension::new(None);
+        let (message, _) = request(&extension, 0);
+        assert!(matches!(message.msg_type, ExtensionMessageType::Reject));
+
+        let reply = serde_bencode::to_bytes(&ExtensionMessage::data(0, 10)).unwrap();
+        let from = "127.0.0.1:6881".parse().unwrap();
+        assert!(matches!(
+            extension.handle(from, &reply).unwrap(),
+            Dispatch::Deliver
+        ));
+    }
+
+    #[test]
+    fn sizes_the_last_piece_to_the_remainder() {
+        assert!(MetadataBuffer::new(0).is_err());
+        assert!(MetadataBuffer::new(MAX_METADATA_SIZE + 1).is_err());
+
+        let mut buffer = MetadataBuffer::new(2 * METADATA_PIECE_LEN + 5).unwrap();
+        assert_eq!(buffer.piece_len(0), METADATA_PIECE_LEN);
+        assert_eq!(buffer.piece_len(2), 5);
+        assert!(buffer.add(2, &[0; 6]).is_err());
+        assert!(buffer.add(2, &[0; 5]).is_ok());
+        assert!(buffer.add(1, &[0; 5]).is_err());
+    }
+
+    #[test]
+    fn hands_out_each_piece_once_until_released() {
+        let mut buffer = MetadataBuffer::new(METADATA_PIECE_LEN + 1).unwrap();
+        assert_eq!(buffer.claim(), Some(0));
+        assert_eq!(buffer.claim(), Some(1));
+        assert_eq!(buffer.claim(), None);
+        buffer.add(0, &[0; METADATA_PIECE_LEN as usize]).unwrap();
+        buffer.release(0);
+        buffer.release(1);
+        assert_eq!(buffer.claim(), Some(1));
+        assert_eq!(buffer.claim(), None);
+    }
+
+    #[test]
+    fn accepts_only_metadata_matching_the_info_hash() {
+        let metadata = metadata();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let mut buffer = MetadataBuffer::new(metadata.len() as u32).unwrap();
+
+        buffer.add(0, &metadata[..16384]).unwrap();
+        assert!(buffer.verified(info_hash).is_none());
+        buffer.add(1, &metadata[16384..]).unwrap();
+        let info = buffer.verified(info_hash).unwrap();
+        assert_eq!(info.hash(), info_hash);
+
+        let mut corrupt = metadata[16384..].to_vec();
+        corrupt[0] ^= 1;
+        buffer.reset();
+        buffer.add(0, &metadata[..16384]).unwrap();
+        buffer.add(1, &corrupt).unwrap();
+        assert!(buffer.is_complete());
+        assert!(buffer.verified(info_hash).is_none());
+    }
+}
@@ -1,20 +1,43 @@
 use anyhow::Context;
 use bitvec::prelude::*;
+use futures_util::{stream::FuturesUnordered, FutureExt, SinkExt, StreamExt};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{mem, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::Mutex,
-    task::JoinSet,
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
+    task::JoinHandle,
 };
+use tokio_util::codec::{FramedRead, FramedWrite};
 
+use crate::choker::{PeerStats, SNUB_TIMEOUT};
 use crate::extension::*;
+use crate::message::{BlockRequest, Message, MessageCodec};
+use crate::metadata::fetch_metadata;
+use crate::piece::PieceBuffer;
+use crate::pipeline::RequestWindow;
+use crate::rate::RateMeter;
 use crate::torrent::Info;
 
-const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB
+pub(crate) const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB
 const EXTENSION_SUPPORT_FLAG: u64 = 1 << 20;
+const HANDSHAKE_LEN: usize = 68;
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+// RFC 8305's recommended head start for each connection attempt.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// For connecting and trading BitTorrent handshakes.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// A peer silent for this long after the handshake is taken to have no pieces.
+const BITFIELD_TIMEOUT: Duration = Duration::from_secs(5);
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Serialize, Deserialize)]
 pub struct Handshake {
@@ -44,166 +67,645 @@ impl Handshake {
     }
 }
 
+// The four flags of BEP 3. Both sides start out choking and not interested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for ChokeState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+// What the reader task and the requesting side share: the blocks we asked for
+// and are still waiting on, the window that limits how many of them there may
+// be, and the choke state.
+#[derive(Default)]
+struct ConnectionState {
+    pending: HashMap<(u32, u32), oneshot::Sender<Vec<u8>>>,
+    window: RequestWindow,
+    choke: ChokeState,
+    // Bytes of PIECE payload we sent the peer.
+    uploaded: u64,
+    upload: RateMeter,
+    // When the last block arrived, or when we started waiting if none has
+    // since. Only meaningful while requests are pending.
+    waiting_since: Option<Instant>,
+    // What we offer the peer, and dispatch its extension messages to.
+    extensions: Arc<ExtensionRegistry>,
+    closed: bool,
+}
+
+// The reader and writer tasks of one peer connection. PIECE replies are routed
+// to whoever requested that (index, begin), the extension handshake is kept
+// aside and extension messages go to their handler; every other message is
+// queued in `incoming` in arrival order, choke and interest messages after
+// they updated the shared state.
+struct Connection {
+    outgoing: mpsc::UnboundedSender<Message>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Message>>,
+    state: Arc<StdMutex<ConnectionState>>,
+    // Signalled whenever a request slot frees up, the peer chokes or unchokes
+    // us, or the connection closes.
+    changed: Arc<Notify>,
+    extension_handshake: watch::Receiver<Option<Arc<ExtensionHeader>>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
 #[derive(Clone)]
 pub struct Peer {
     pub address: SocketAddr,
     pub id: [u8; 20],
-    pub stream: Arc<Mutex<TcpStream>>,
     pub supports_extension: bool,
     pub metadata_extension_id: Option<u8>,
+    // Size of the info dictionary, if the peer offered it over ut_metadata.
+    pub metadata_size: Option<u32>,
+    info_hash: [u8; 20],
+    connection: Arc<Connection>,
 }
 
 impl Peer {
     pub async fn new(address: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+        Self::connect(&[address], info_hash).await
+    }
+
+    // Connects to a peer known by several addresses, e.g. both an IPv4 and an
+    // IPv6 one, using whichever connects first.
+    pub async fn connect(addresses: &[SocketAddr], info_hash: [u8; 20]) -> anyhow::Result<Self> {
         let mut handshake = Handshake::new(info_hash);
         let mut handshake_bytes = bincode::serialize(&handshake)?;
 
-        let mut peer_stream = TcpStream::connect(address)
+        let (peer_stream, address) = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let (mut peer_stream, address) = connect_any(addresses)
+                .await
+                .context("failed to connect to peer")?;
+            peer_stream
+                .write_all(&handshake_bytes)
+                .await
+                .context("failed to send handshake")?;
+            peer_stream
+                .read_exact(&mut handshake_bytes)
+                .await
+                .context("failed to receive handshake")?;
+            anyhow::Ok((peer_stream, address))
+        })
+        .await
+        .context("timed out connecting to peer")??;
+
+        handshake = bincode::deserialize(&handshake_bytes)?;
+        let peer = Peer {
+            address,
+            id: handshake.peer_id,
+            supports_extension: handshake.supports_extension(),
+            metadata_extension_id: None,
+            metadata_size: None,
+            info_hash,
+            connection: Arc::new(Self::spawn_connection(peer_stream, address)),
+        };
+        Ok(peer)
+    }
+
+    // Takes an inbound connection: reads the peer's handshake, checks with
+    // `serves` that we have the torrent it asks for and answers with our own.
+    pub async fn accept(
+        mut stream: TcpStream,
+        address: SocketAddr,
+        serves: impl Fn(&[u8; 20]) -> bool,
+    ) -> anyhow::Result<(Self, [u8; 20])> {
+        let mut handshake_bytes = [0u8; HANDSHAKE_LEN];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut handshake_bytes))
             .await
-            .context("failed to connect to peer")?;
-        peer_stream
-            .write_all(&handshake_bytes)
+            .context("timed out waiting for handshake")?
+            .context("failed to receive handshake")?;
+        let handshake: Handshake = bincode::deserialize(&handshake_bytes)?;
+        anyhow::ensure!(
+            handshake.length == 19 && &handshake.protocol == b"BitTorrent protocol",
+            "not a BitTorrent handshake"
+        );
+        anyhow::ensure!(
+            serves(&handshake.info_hash),
+            "not serving info hash {}",
+            hex::encode(handshake.info_hash)
+        );
+        let reply = bincode::serialize(&Handshake::new(handshake.info_hash))?;
+        stream
+            .write_all(&reply)
             .await
             .context("failed to send handshake")?;
-        peer_stream
-            .read_exact(&mut handshake_bytes)
-            .await
-            .context("failed to receive handshake")?;
 
-        handshake = bincode::deserialize(&handshake_bytes)?;
         let peer = Peer {
             address,
             id: handshake.peer_id,
-            stream: Arc::new(Mutex::new(peer_stream)),
             supports_extension: handshake.supports_extension(),
             metadata_extension_id: None,
+            metadata_size: None,
+            info_hash: handshake.info_hash,
+            connection: Arc::new(Self::spawn_connection(stream, address)),
         };
-        Ok(peer)
+        Ok((peer, handshake.info_hash))
+    }
+
+    fn spawn_connection(stream: TcpStream, address: SocketAddr) -> Connection {
+        let (read_half, write_half) = stream.into_split();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (extension_tx, extension_handshake) = watch::channel(None::<Arc<ExtensionHeader>>);
+        let state = Arc::new(StdMutex::new(ConnectionState::default()));
+        let changed = Arc::new(Notify::new());
+
+        let writer = tokio::spawn(async move {
+            let mut sink = FramedWrite::new(write_half, MessageCodec);
+            while let Some(msg) = outgoing_rx.recv().await {
+                if sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let reader_state = state.clone();
+        let reader_changed = changed.clone();
+        let reader_outgoing = outgoing.clone();
+        let reader = tokio::spawn(async move {
+            let mut stream = FramedRead::new(read_half, MessageCodec);
+            while let Some(Ok(msg)) = stream.next().await {
+                // Messages for our extensions go to their handler, which may
+                // still want them queued.
+                let msg = match msg {
+                    Message::Extension { id, payload } if id != 0 => {
+                        let extensions = reader_state.lock().unwrap().extensions.clone();
+                        let Some(handler) = extensions.handler(id) else {
+                            continue;
+                        };
+                        match handler.handle(address, &payload) {
+                            Ok(Dispatch::Reply(reply)) => {
+                                let remote_id = extension_tx
+                                    .borrow()
+                                    .as_ref()
+                                    .and_then(|header| header.id(handler.name()));
+                                if let Some(id) = remote_id {
+                                    let reply = Message::Extension { id, payload: reply };
+                                    let _ = reader_outgoing.send(reply);
+                                }
+                                continue;
+                            }
+                            Ok(Dispatch::Deliver) => Message::Extension { id, payload },
+                            // Malformed messages are dropped like ones for ids we never gave out.
+                            Ok(Dispatch::Done) | Err(_) => continue,
+                        }
+                    }
+                    msg => msg,
+                };
+                match msg {
+                    Message::Choke => {
+                        // The peer discards our requests when it chokes us. Dropping
+                        // the waiters tells `load_piece` to ask again after UNCHOKE.
+                        let mut guard = reader_state.lock().unwrap();
+                        let state = &mut *guard;
+                        state.choke.peer_choking = true;
+                        for _ in state.pending.drain() {
+                            state.window.release();
+                        }
+                    }
+                    Message::Unchoke => reader_state.lock().unwrap().choke.peer_choking = false,
+                    Message::Interested => {
+                        reader_state.lock().unwrap().choke.peer_interested = true
+                    }
+                    Message::NotInterested => {
+                        reader_state.lock().unwrap().choke.peer_interested = false
+                    }
+                    _ => {}
+                }
+                match msg {
+                    Message::KeepAlive => {}
+                    Message::Piece { index, begin, data } => {
+                        let waiter = {
+                            let mut state = reader_state.lock().unwrap();
+                            let waiter = state.pending.remove(&(index, begin));
+                            if waiter.is_some() {
+                                let now = Instant::now();
+                                state.window.on_block(data.len(), now);
+                                state.waiting_since = Some(now);
+                            }
+                            waiter
+                        };
+                        // Unrequested blocks, or ones nobody waits for anymore, are dropped.
+                        if let Some(waiter) = waiter {
+                            reader_changed.notify_waiters();
+                            let _ = waiter.send(data);
+                        }
+                    }
+                    Message::Extension { id: 0, payload } => {
+                        // A handshake we can't read counts as none at all.
+                        let Ok(header) = serde_bencode::from_bytes::<ExtensionHeader>(&payload)
+                        else {
+                            continue;
+                        };
+                        if let Some(reqq) = header.reqq {
+                            let reqq = reqq.max(1) as usize;
+                            reader_state.lock().unwrap().window.set_peer_limit(reqq);
+                        }
+                        extension_tx.send_replace(Some(Arc::new(header)));
+                    }
+                    msg => {
+                        if matches!(msg, Message::Choke | Message::Unchoke) {
+                            reader_changed.notify_waiters();
+                        }
+                        if incoming_tx.send(msg).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            // Fail everything still in flight instead of leaving it hanging.
+            let mut state = reader_state.lock().unwrap();
+            state.pending.clear();
+            state.closed = true;
+            drop(state);
+            reader_changed.notify_waiters();
+        });
+
+        Connection {
+            outgoing,
+            incoming: Mutex::new(incoming),
+            state,
+            changed,
+            extension_handshake,
+            reader,
+            writer,
+        }
+    }
+
+    // Bounds the number of outstanding block requests; within them the window
+    // follows the peer's download rate.
+    pub fn set_request_limits(&self, min: usize, max: usize) {
+        let mut state = self.connection.state.lock().unwrap();
+        state.window.set_limits(min, max);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.connection.state.lock().unwrap().closed
+    }
+
+    pub fn choke_state(&self) -> ChokeState {
+        self.connection.state.lock().unwrap().choke
+    }
+
+    // Sends INTERESTED or NOT_INTERESTED if our interest actually changed.
+    pub async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        let changed = {
+            let mut state = self.connection.state.lock().unwrap();
+            let changed = state.choke.am_interested != interested;
+            state.choke.am_interested = interested;
+            changed
+        };
+        if !changed {
+            return Ok(());
+        }
+        let msg = if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        };
+        self.send(msg).await
+    }
+
+    // Sends CHOKE or UNCHOKE if our choking of the peer actually changed.
+    pub async fn set_choking(&mut self, choking: bool) -> anyhow::Result<()> {
+        let changed = {
+            let mut state = self.connection.state.lock().unwrap();
+            let changed = state.choke.am_choking != choking;
+            state.choke.am_choking = choking;
+            changed
+        };
+        if !changed {
+            return Ok(());
+        }
+        let msg = if choking {
+            Message::Choke
+        } else {
+            Message::Unchoke
+        };
+        self.send(msg).await
+    }
+
+    // How many block requests may currently be outstanding.
+    pub fn request_limit(&self) -> usize {
+        self.connection.state.lock().unwrap().window.limit()
     }
 
+    // Answers a REQUEST, counting the block towards what we uploaded.
+    pub async fn send_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let len = data.len();
+        self.send(Message::Piece { index, begin, data }).await?;
+        let mut state = self.connection.state.lock().unwrap();
+        state.uploaded += len as u64;
+        state.upload.add(len, Instant::now());
+        Ok(())
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.connection.state.lock().unwrap().uploaded
+    }
+
+    // Bytes per second received from this peer.
+    pub fn download_rate(&self) -> f64 {
+        self.connection
+            .state
+            .lock()
+            .unwrap()
+            .window
+            .rate(Instant::now())
+    }
+
+    // Bytes per second sent to this peer.
+    pub fn upload_rate(&self) -> f64 {
+        self.connection
+            .state
+            .lock()
+            .unwrap()
+            .upload
+            .rate(Instant::now())
+    }
+
+    // Whether the peer has left our requests unanswered for SNUB_TIMEOUT.
+    pub fn is_snubbed(&self) -> bool {
+        let state = self.connection.state.lock().unwrap();
+        !state.pending.is_empty()
+            && state
+                .waiting_since
+                .is_some_and(|since| since.elapsed() >= SNUB_TIMEOUT)
+    }
+
+    // A snapshot for the choker, which identifies the peer by `key`.
+    pub fn choker_stats<K>(&self, key: K) -> PeerStats<K> {
+        PeerStats {
+            key,
+            interested: self.choke_state().peer_interested,
+            download_rate: self.download_rate(),
+            upload_rate: self.upload_rate(),
+            snubbed: self.is_snubbed(),
+        }
+    }
+
+    // Sets the extensions we offer; call it before sending our handshake so no
+    // message for them can arrive first.
+    pub fn set_extensions(&self, extensions: Arc<ExtensionRegistry>) {
+        self.connection.state.lock().unwrap().extensions = extensions;
+    }
+
+    pub async fn send_extension_handshake(&mut self) -> anyhow::Result<()> {
+        let (extensions, reqq) = {
+            let state = self.connection.state.lock().unwrap();
+            (state.extensions.clone(), state.window.max())
+        };
+        let payload = serde_bencode::to_bytes(&extensions.handshake(self.address, reqq))?;
+        self.send(Message::Extension { id: 0, payload }).await
+    }
+
+    // Trades extension handshakes and waits for the peer's.
     pub async fn extension_handshake(&mut self) -> anyhow::Result<()> {
-        let ext_header = ExtensionHeader::new();
-        let mut payload = serde_bencode::to_bytes(&ext_header)?;
-        payload.insert(0, 0);
-
-        let handshake = Message::new(MessageId::EXTENSION, payload);
-        self.send(handshake).await?;
-        let reply = self.recv().await?;
-        let ext_header = serde_bencode::from_bytes::<ExtensionHeader>(&reply.payload[1..])?;
-        self.metadata_extension_id = Some(ext_header.m.ut_metadata);
+        self.send_extension_handshake().await?;
+        // The reader task keeps the peer's handshake aside, whenever it arrives.
+        let mut handshake = self.connection.extension_handshake.clone();
+        let wait = async {
+            loop {
+                if let Some(header) = handshake.borrow_and_update().clone() {
+                    return anyhow::Ok(header);
+                }
+                handshake
+                    .changed()
+                    .await
+                    .map_err(|_| anyhow::anyhow!("peer closed the connection"))?;
+            }
+        };
+        let ext_header = tokio::time::timeout(EXTENSION_HANDSHAKE_TIMEOUT, wait)
+            .await
+            .context("timed out waiting for extension handshake")??;
+        self.metadata_extension_id = ext_header.id(UT_METADATA);
+        self.metadata_size = ext_header.metadata_size;
         Ok(())
     }
 
+    // The peer's extension handshake, once it arrived.
+    pub fn extensions(&self) -> Option<Arc<ExtensionHeader>> {
+        self.connection.extension_handshake.borrow().clone()
+    }
+
+    // The client name and version the peer announced.
+    pub fn client(&self) -> Option<String> {
+        self.extensions()?.client()
+    }
+
+    // Our address as the peer sees it.
+    pub fn yourip(&self) -> Option<IpAddr> {
+        self.extensions()?.yourip()
+    }
+
+    // How many requests the peer queues for us.
+    pub fn reqq(&self) -> Option<u32> {
+        self.extensions()?.reqq
+    }
+
+    // The port the peer accepts connections on, which for an inbound
+    // connection is not the one it came from.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.extensions()?.p
+    }
+
+    // Sends an extension message on the id the peer chose for `name`.
+    pub async fn send_extension(&mut self, name: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let id = self
+            .extensions()
+            .and_then(|header| header.id(name))
+            .with_context(|| format!("peer does not support {}", name))?;
+        self.send(Message::Extension { id, payload }).await
+    }
+
+    // Fetches the info dictionary from this peer alone.
     pub async fn extension_metadata(&mut self) -> anyhow::Result<Info> {
-        let ext_msg = ExtensionMessage {
-            msg_type: ExtensionMessageType::Request,
-            piece: 0,
-            total_size: None,
-        };
-        let mut payload = serde_bencode::to_bytes(&ext_msg)?;
-        let extension_msg_id = self
-            .metadata_extension_id
-            .expect("metadata extension id should be set during handshake");
-        payload.insert(0, extension_msg_id);
-
-        let msg = Message::new(MessageId::EXTENSION, payload);
-        self.send(msg).await?;
-        let reply = self.recv().await?;
-        let ext_msg = serde_bencode::from_bytes::<ExtensionMessage>(&reply.payload[1..])?;
-        let metadata_piece_len = ext_msg.total_size.unwrap();
-        let metadata = &reply.payload[reply.payload.len() - metadata_piece_len as usize..];
-        let torrent_info = serde_bencode::from_bytes::<Info>(metadata)?;
-        Ok(torrent_info)
-    }
-
-    async fn recv(&mut self) -> anyhow::Result<Message> {
-        let mut stream = self.stream.lock().await;
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf).await?;
-        let length = u32::from_be_bytes(buf);
-
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
-        let id: MessageId = unsafe { mem::transmute(buf[0]) };
-
-        let mut buf = vec![0u8; length as usize - mem::size_of::<MessageId>()];
-        stream.read_exact(&mut buf).await?;
-        Ok(Message {
-            length,
-            id,
-            payload: buf,
-        })
+        fetch_metadata(vec![self.clone()], self.info_hash).await
     }
 
-    async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(&msg.as_bytes()).await?;
-        Ok(())
+    // Asks for one piece of the info dictionary and waits for the Data or
+    // Reject reply, returning it with the piece data.
+    pub async fn request_metadata_piece(
+        &mut self,
+        piece: u32,
+    ) -> anyhow::Result<(ExtensionMessage, Vec<u8>)> {
+        let local_id = self
+            .connection
+            .state
+            .lock()
+            .unwrap()
+            .extensions
+            .id(UT_METADATA)
+            .context("ut_metadata is not enabled for this peer")?;
+        let payload = serde_bencode::to_bytes(&ExtensionMessage::request(piece))?;
+        self.send_extension(UT_METADATA, payload).await?;
+        let reply = async {
+            loop {
+                let Message::Extension { id, payload } = self.recv().await? else {
+                    continue;
+                };
+                if id != local_id {
+                    continue;
+                }
+                let (message, data) = ExtensionMessage::parse(&payload)?;
+                // Late replies to earlier requests are skipped.
+                if message.piece == piece {
+                    return Ok((message, data.to_vec()));
+                }
+            }
+        };
+        tokio::time::timeout(METADATA_TIMEOUT, reply)
+            .await
+            .context("timed out waiting for metadata")?
+    }
+
+    // Next non-PIECE message; keep-alives never get this far.
+    pub async fn recv(&mut self) -> anyhow::Result<Message> {
+        let mut incoming = self.connection.incoming.lock().await;
+        incoming.recv().await.context("peer closed the connection")
     }
 
-    pub async fn get_pieces(&mut self) -> anyhow::Result<Vec<usize>> {
-        let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::BITFIELD);
-        let bitfield = BitVec::<u8, Msb0>::from_vec(msg.payload);
-        let pieces = bitfield.iter_ones().collect();
-        Ok(pieces)
+    pub async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        self.connection
+            .outgoing
+            .send(msg)
+            .map_err(|_| anyhow::anyhow!("peer closed the connection"))
+    }
+
+    // The pieces in the peer's BITFIELD. BEP 3 lets a peer with nothing leave
+    // it out, so silence means no pieces and a HAVE in its place just the one.
+    // Any other first message is handed back for the caller to handle.
+    pub async fn initial_pieces(&mut self) -> anyhow::Result<(Vec<usize>, Option<Message>)> {
+        let Ok(msg) = tokio::time::timeout(BITFIELD_TIMEOUT, self.recv()).await else {
+            return Ok((vec![], None));
+        };
+        match msg? {
+            Message::Bitfield(bitfield) => {
+                let bitfield = BitVec::<u8, Msb0>::from_vec(bitfield);
+                Ok((bitfield.iter_ones().collect(), None))
+            }
+            Message::Have(piece) => Ok((vec![piece as usize], None)),
+            msg => Ok((vec![], Some(msg))),
+        }
     }
 
     pub async fn prepare_download(&mut self) -> anyhow::Result<()> {
-        let interested = Message::new(MessageId::INTERESTED, vec![]);
-        self.send(interested).await?;
-        let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::UNCHOKE);
-        Ok(())
+        self.set_interested(true).await?;
+        self.wait_for(|state| (!state.choke.peer_choking).then_some(()))
+            .await
     }
 
+    // Downloads a whole piece from this peer alone.
     pub async fn load_piece(&mut self, index: u32, piece_len: u32) -> anyhow::Result<Vec<u8>> {
-        let mut piece = vec![0u8; piece_len as usize];
-        let mut join_set = JoinSet::new();
-
-        let spawn = |join_set: &mut JoinSet<_>, mut peer: Peer, offset: u32| {
-            let length = BLOCK_SIZE.min(piece_len - offset);
-            join_set.spawn(async move {
-                match peer.load_block(index, offset, length).await {
-                    Ok(msg) => (offset, msg.payload[8..].to_vec()),
-                    Err(err) => {
-                        eprintln!("Error loading block: {}. Will retry...", err);
-                        (offset, vec![])
+        let piece = PieceBuffer::new(index, piece_len);
+        self.fetch_blocks(&piece).await?;
+        Ok(piece.data())
+    }
+
+    // Requests the piece's missing blocks as the request window allows and
+    // stores the replies as the reader task routes them back. Returns once the
+    // piece is complete, which other peers may have helped with, along with the
+    // number of bytes that arrived after someone else had delivered them.
+    pub async fn fetch_blocks(&mut self, piece: &PieceBuffer) -> anyhow::Result<u64> {
+        let mut queue: VecDeque<_> = piece.missing().into();
+        let mut requested = RequestedBlocks {
+            connection: &self.connection,
+            blocks: Vec::new(),
+        };
+        let mut in_flight = FuturesUnordered::new();
+        let mut duplicate_bytes = 0;
+
+        let collect = async {
+            loop {
+                let arrived = piece.arrived();
+                // Blocks another peer delivered meanwhile are no longer needed from us.
+                queue.retain(|block| !piece.is_received(block.begin));
+                requested.cancel(|block| piece.is_received(block.begin));
+                if piece.is_complete() || (queue.is_empty() && in_flight.is_empty()) {
+                    return Ok(());
+                }
+                tokio::select! {
+                    slot = self.acquire_slot(), if !queue.is_empty() => {
+                        slot?;
+                        let block = queue.pop_front().expect("checked above");
+                        in_flight.push(requested.request(block)?.map(move |data| (block, data)));
                     }
+                    Some((block, data)) = in_flight.next() => match data {
+                        Ok(data) => {
+                            if !piece.add(block.begin, &data)? {
+                                duplicate_bytes += data.len() as u64;
+                            }
+                        }
+                        // Cancelled because it arrived from another peer.
+                        Err(_) if piece.is_received(block.begin) => {}
+                        // Choked before the peer answered; the next slot we get is
+                        // after UNCHOKE.
+                        Err(_) => {
+                            anyhow::ensure!(!self.is_closed(), "peer closed the connection");
+                            queue.push_back(block);
+                        }
+                    },
+                    _ = arrived => {}
                 }
-            });
+            }
         };
+        tokio::time::timeout(PIECE_TIMEOUT, collect)
+            .await
+            .context("timed out waiting for piece")??;
+        Ok(duplicate_bytes)
+    }
 
-        for offset in (0..piece_len).step_by(BLOCK_SIZE as usize) {
-            spawn(&mut join_set, self.clone(), offset);
-        }
+    // Waits until the peer has us unchoked and the request window has room,
+    // and takes a slot.
+    async fn acquire_slot(&self) -> anyhow::Result<()> {
+        self.wait_for(|state| {
+            (!state.choke.peer_choking && state.window.try_acquire()).then_some(())
+        })
+        .await
+    }
 
-        while let Some(join_result) = join_set.join_next().await {
-            let (offset, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                spawn(&mut join_set, self.clone(), offset);
-            } else {
-                let start = offset as usize;
-                let end = start + data.len();
-                piece[start..end].copy_from_slice(&data);
+    // Re-runs `check` on every state change until it yields a value.
+    async fn wait_for<T>(
+        &self,
+        mut check: impl FnMut(&mut ConnectionState) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        loop {
+            let changed = self.connection.changed.notified();
+            {
+                let mut state = self.connection.state.lock().unwrap();
+                if let Some(value) = check(&mut state) {
+                    return Ok(value);
+                }
+                anyhow::ensure!(!state.closed, "peer closed the connection");
             }
+            changed.await;
         }
-
-        Ok(piece)
-    }
-
-    async fn load_block(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<Message> {
-        let payload = vec![
-            index.to_be_bytes(),
-            begin.to_be_bytes(),
-            length.to_be_bytes(),
-        ]
-        .concat();
-        let request = Message::new(MessageId::REQUEST, payload);
-        self.send(request).await?;
-        let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::PIECE);
-        Ok(msg)
     }
 
     pub fn gen_peer_id() -> String {
@@ -214,39 +716,233 @@ impl Peer {
     }
 }
 
-#[derive(Debug)]
-struct Message {
-    length: u32,
-    id: MessageId,
-    payload: Vec<u8>,
+// Happy Eyeballs (RFC 8305): tries the addresses IPv6 first, alternating
+// families, and starts the next attempt as soon as one fails or has gone
+// CONNECTION_ATTEMPT_DELAY without connecting. The first connection wins.
+async fn connect_any(addresses: &[SocketAddr]) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addresses.iter().partition(|address| address.is_ipv6());
+    let mut ordered = Vec::with_capacity(addresses.len());
+    for i in 0..v6.len().max(v4.len()) {
+        ordered.extend(v6.get(i));
+        ordered.extend(v4.get(i));
+    }
+
+    let mut remaining = ordered.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = None;
+    loop {
+        if let Some(address) = remaining.next() {
+            attempts.push(async move { (address, TcpStream::connect(address).await) });
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, address)),
+                Err(e) => error = Some(e),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if !remaining.as_slice().is_empty() => {}
+        }
+    }
+    Err(error.map_or_else(|| anyhow::anyhow!("no address to connect to"), Into::into))
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-#[repr(u8)]
-enum MessageId {
-    BITFIELD = 5,
-    INTERESTED = 2,
-    UNCHOKE = 1,
-    REQUEST = 6,
-    PIECE = 7,
-    EXTENSION = 20,
+// The requests of one `fetch_blocks` call. Whatever is still outstanding when
+// it ends, by completing, failing, timing out or being dropped, is cancelled so
+// the peer doesn't send blocks nobody will read.
+struct RequestedBlocks<'a> {
+    connection: &'a Connection,
+    blocks: Vec<BlockRequest>,
 }
 
-impl Message {
-    fn new(id: MessageId, payload: Vec<u8>) -> Self {
-        let length = (mem::size_of::<MessageId>() + payload.len()) as u32;
-        Self {
-            length,
-            id,
-            payload,
+impl RequestedBlocks<'_> {
+    // Sends a request for which a window slot has already been taken.
+    fn request(&mut self, block: BlockRequest) -> anyhow::Result<oneshot::Receiver<Vec<u8>>> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.connection.state.lock().unwrap();
+        if state.pending.is_empty() {
+            state.waiting_since = Some(Instant::now());
+        }
+        state.pending.insert((block.index, block.begin), tx);
+        drop(state);
+        self.blocks.push(block);
+        self.connection
+            .outgoing
+            .send(Message::Request(block))
+            .map_err(|_| anyhow::anyhow!("peer closed the connection"))?;
+        Ok(rx)
+    }
+
+    // Sends CANCEL for the selected blocks that are still outstanding.
+    fn cancel(&mut self, mut redundant: impl FnMut(&BlockRequest) -> bool) {
+        let mut state = self.connection.state.lock().unwrap();
+        let mut cancelled = false;
+        self.blocks.retain(|block| {
+            if !redundant(block) {
+                return true;
+            }
+            if state.pending.remove(&(block.index, block.begin)).is_some() {
+                state.window.release();
+                let _ = self.connection.outgoing.send(Message::Cancel(*block));
+                cancelled = true;
+            }
+            false
+        });
+        drop(state);
+        if cancelled {
+            self.connection.changed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for RequestedBlocks<'_> {
+    fn drop(&mut self) {
+        self.cancel(|_| true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const PIECE_LEN: u32 = 3 * BLOCK_SIZE;
+
+    // A remote peer on localhost that has unchoked us, and our connection to it.
+    async fn stand_in() -> (Peer, Framed<TcpStream, MessageCodec>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let remote = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; HANDSHAKE_LEN]).await.unwrap();
+            let handshake = bincode::serialize(&Handshake::new(INFO_HASH)).unwrap();
+            stream.write_all(&handshake).await.unwrap();
+            let mut framed = Framed::new(stream, MessageCodec);
+            framed.send(Message::Unchoke).await.unwrap();
+            framed
+        };
+        let (peer, remote) = tokio::join!(Peer::new(address, INFO_HASH), remote);
+        (peer.unwrap(), remote)
+    }
+
+    // The blocks the remote is asked for, skipping other messages.
+    async fn requests(remote: &mut Framed<TcpStream, MessageCodec>, n: usize) -> Vec<u32> {
+        let mut begins = Vec::new();
+        while begins.len() < n {
+            if let Message::Request(block) = remote.next().await.unwrap().unwrap() {
+                begins.push(block.begin);
+            }
+        }
+        begins
+    }
+
+    async fn cancels(remote: &mut Framed<TcpStream, MessageCodec>, n: usize) -> Vec<u32> {
+        let mut begins = Vec::new();
+        while begins.len() < n {
+            if let Message::Cancel(block) = remote.next().await.unwrap().unwrap() {
+                begins.push(block.begin);
+            }
+        }
+        begins
+    }
+
+    fn block(begin: u32) -> Message {
+        Message::Piece {
+            index: 0,
+            begin,
+            data: vec![begin as u8; BLOCK_SIZE as usize],
         }
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(self.length.to_be_bytes());
-        bytes.push(self.id as u8);
-        bytes.extend(self.payload.as_slice());
-        bytes
+    #[tokio::test]
+    async fn cancels_the_requests_another_peer_answered_first() {
+        let ((mut fast, mut fast_remote), (mut slow, mut slow_remote)) =
+            tokio::join!(stand_in(), stand_in());
+        let piece = PieceBuffer::new(0, PIECE_LEN);
+
+        let (slow_asked, asked) = oneshot::channel();
+        let fast_side = async {
+            let begins = requests(&mut fast_remote, 3).await;
+            asked.await.unwrap();
+            for begin in begins {
+                fast_remote.send(block(begin)).await.unwrap();
+            }
+        };
+        let slow_side = async {
+            // Endgame: both peers are asked for every block.
+            let mut begins = requests(&mut slow_remote, 3).await;
+            begins.sort();
+            assert_eq!(begins, [0, BLOCK_SIZE, 2 * BLOCK_SIZE]);
+            slow_asked.send(()).unwrap();
+            let mut cancelled = cancels(&mut slow_remote, 3).await;
+            cancelled.sort();
+            assert_eq!(cancelled, begins);
+        };
+        let (fast_duplicates, slow_duplicates, (), ()) = tokio::join!(
+            fast.fetch_blocks(&piece),
+            slow.fetch_blocks(&piece),
+            fast_side,
+            slow_side
+        );
+        assert_eq!(fast_duplicates.unwrap(), 0);
+        assert_eq!(slow_duplicates.unwrap(), 0);
+        assert!(piece.is_complete());
+        assert_eq!(
+            slow.connection.state.lock().unwrap().window.outstanding(),
+            0
+        );
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn cancels_blocks_that_arrive_from_elsewhere_while_waiting() {
+        let (mut peer, mut remote) = stand_in().await;
+        let piece = PieceBuffer::new(0, PIECE_LEN);
+        piece.add(0, &[1; BLOCK_SIZE as usize]).unwrap();
+
+        let mut fetch = pin!(peer.fetch_blocks(&piece));
+        let begins = tokio::select! {
+            _ = &mut fetch => panic!("piece completed without the remote"),
+            begins = requests(&mut remote, 2) => begins,
+        };
+        assert_eq!(begins, [BLOCK_SIZE, 2 * BLOCK_SIZE]);
+        piece.add(BLOCK_SIZE, &[2; BLOCK_SIZE as usize]).unwrap();
+
+        let remote_side = async {
+            assert_eq!(cancels(&mut remote, 1).await, [BLOCK_SIZE]);
+            remote.send(block(2 * BLOCK_SIZE)).await.unwrap();
+        };
+        let (duplicates, ()) = tokio::join!(fetch, remote_side);
+        assert_eq!(duplicates.unwrap(), 0);
+        assert_eq!(piece.data()[BLOCK_SIZE as usize], 2);
+    }
+
+    #[tokio::test]
+    async fn counts_blocks_that_arrive_after_another_peer_delivered_them() {
+        let (mut peer, mut remote) = stand_in().await;
+        let piece = PieceBuffer::new(0, PIECE_LEN);
+        let state = peer.connection.state.clone();
+
+        let mut fetch = pin!(peer.fetch_blocks(&piece));
+        let begins = tokio::select! {
+            _ = &mut fetch => panic!("piece completed without the remote"),
+            begins = requests(&mut remote, 3) => begins,
+        };
+        for begin in begins {
+            remote.send(block(begin)).await.unwrap();
+        }
+        // Let the replies reach our side before the other peer's copy of block 0.
+        while !state.lock().unwrap().pending.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        piece.add(0, &[9; BLOCK_SIZE as usize]).unwrap();
+
+        assert_eq!(fetch.await.unwrap(), BLOCK_SIZE as u64);
+        assert!(piece.is_complete());
+        assert_eq!(piece.data()[0], 9);
+    }
+}
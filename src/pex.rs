@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    extension::{Dispatch, ExtensionHandler},
+    tracker::{parse_compact_peers, parse_compact_peers6},
+};
+
+// BEP 11: https://www.bittorrent.org/beps/bep_0011.html
+pub const UT_PEX: &str = "ut_pex";
+// A peer may be sent at most one message a minute...
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// ...with at most this many added and this many dropped peers.
+const MAX_PEX_PEERS: usize = 50;
+
+// Flags in `added.f`, one byte per added peer.
+pub const PEX_SEED: u8 = 0x02;
+// We made an outgoing connection to the peer, so others can too.
+pub const PEX_REACHABLE: u8 = 0x10;
+
+// Peers in compact format. Some clients insist on the IPv4 keys even when
+// empty; the IPv6 ones are left out then.
+#[derive(Default, Serialize, Deserialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_f: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f", skip_serializing_if = "Option::is_none")]
+    added6_f: Option<ByteBuf>,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped6: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub address: SocketAddr,
+    pub flags: u8,
+}
+
+fn bytes(field: &Option<ByteBuf>) -> &[u8] {
+    field.as_ref().map_or(&[], |b| b.as_slice())
+}
+
+// The peers a message adds, leaving out any it drops again.
+fn parse_added(payload: &[u8]) -> anyhow::Result<Vec<PexPeer>> {
+    let message: PexMessage = serde_bencode::from_bytes(payload)?;
+    // A list with a partial entry is misaligned, so none of it can be trusted.
+    anyhow::ensure!(
+        message.added.len().is_multiple_of(6)
+            && message.dropped.len().is_multiple_of(6)
+            && bytes(&message.added6).len().is_multiple_of(18)
+            && bytes(&message.dropped6).len().is_multiple_of(18),
+        "compact peer list of the wrong length"
+    );
+    let dropped: HashSet<_> = parse_compact_peers(&message.dropped)
+        .into_iter()
+        .chain(parse_compact_peers6(bytes(&message.dropped6)))
+        .collect();
+    let with_flags = |addresses: Vec<SocketAddr>, flags: &[u8]| {
+        let flags = flags.to_vec();
+        addresses
+            .into_iter()
+            .enumerate()
+            .map(move |(i, address)| PexPeer {
+                address,
+                // Flags are optional, and may be fewer than the peers.
+                flags: flags.get(i).copied().unwrap_or(0),
+            })
+    };
+    let added = with_flags(parse_compact_peers(&message.added), &message.added_f)
+        .chain(with_flags(
+            parse_compact_peers6(bytes(&message.added6)),
+            bytes(&message.added6_f),
+        ))
+        .filter(|peer| !dropped.contains(&peer.address) && peer.address.port() != 0)
+        .collect();
+    Ok(added)
+}
+
+// ut_pex: passes the peers others tell us about on to `peers`, or ignores them
+// when there is nobody to take them.
+pub struct PexExtension {
+    peers: Option<mpsc::UnboundedSender<Vec<PexPeer>>>,
+}
+
+impl PexExtension {
+    pub fn new(peers: Option<mpsc::UnboundedSender<Vec<PexPeer>>>) -> Self {
+        Self { peers }
+    }
+}
+
+impl ExtensionHandler for PexExtension {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn handle(&self, _from: SocketAddr, payload: &[u8]) -> anyhow::Result<Dispatch> {
+        let added = parse_added(payload)?;
+        if let Some(peers) = &self.peers {
+            if !added.is_empty() {
+                let _ = peers.send(added);
+            }
+        }
+        Ok(Dispatch::Done)
+    }
+}
+
+// The peers one connection has heard about from us, so it is only sent the
+// changes. Call `update` no more often than every PEX_INTERVAL.
+#[derive(Default)]
+pub struct PexState {
+    known: HashSet<SocketAddr>,
+}
+
+impl PexState {
+    // The message telling the peer at `to` about the changes in `connected`, if
+    // there are any. Whatever doesn't fit is sent next time.
+    pub fn update(&mut self, connected: &[PexPeer], to: SocketAddr) -> Option<Vec<u8>> {
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|peer| peer.address != to && !self.known.contains(&peer.address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let current: HashSet<_> = connected.iter().map(|peer| peer.address).collect();
+        let dropped: Vec<_> = self
+            .known
+            .iter()
+            .filter(|address| !current.contains(address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for address in &dropped {
+            self.known.remove(address);
+        }
+        self.known.extend(added.iter().map(|peer| peer.address));
+
+        let (added4, added6): (Vec<PexPeer>, Vec<PexPeer>) =
+            added.into_iter().partition(|peer| peer.address.is_ipv4());
+        let (dropped4, dropped6): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            dropped.into_iter().partition(SocketAddr::is_ipv4);
+        let flags =
+            |peers: &[PexPeer]| ByteBuf::from(peers.iter().map(|p| p.flags).collect::<Vec<_>>());
+        let addresses = |peers: &[PexPeer]| compact(peers.iter().map(|p| p.address));
+        let message = PexMessage {
+            added: addresses(&added4),
+            added_f: flags(&added4),
+            added6: (!added6.is_empty()).then(|| addresses(&added6)),
+            added6_f: (!added6.is_empty()).then(|| flags(&added6)),
+            dropped: compact(dropped4),
+            dropped6: (!dropped6.is_empty()).then(|| compact(dropped6)),
+        };
+        serde_bencode::to_bytes(&message).ok()
+    }
+}
+
+// Compact peer format: the IP address followed by the port, big-endian.
+pub(crate) fn compact(addresses: impl IntoIterator<Item = SocketAddr>) -> ByteBuf {
+    let mut bytes = Vec::new();
+    for address in addresses {
+        match address.ip() {
+            IpAddr::V4(ip) => bytes.extend(ip.octets()),
+            IpAddr::V6(ip) => bytes.extend(ip.octets()),
+        }
+        bytes.extend(address.port().to_be_bytes());
+    }
+    ByteBuf::from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str, flags: u8) -> PexPeer {
+        PexPeer {
+            address: address.parse().unwrap(),
+            flags,
+        }
+    }
+
+    fn message(payload: &[u8]) -> PexMessage {
+        serde_bencode::from_bytes(payload).unwrap()
+    }
+
+    const TO: &str = "10.0.0.1:6881";
+
+    #[test]
+    fn sends_only_what_changed_since_last_time() {
+        let mut state = PexState::default();
+        let a = peer("10.0.0.2:1", PEX_SEED);
+        let b = peer("10.0.0.3:2", PEX_REACHABLE);
+        let c = peer("10.0.0.4:3", 0);
+        let to = TO.parse().unwrap();
+
+        let first = state.update(&[peer(TO, 0), a, b], to).unwrap();
+        assert_eq!(parse_added(&first).unwrap(), [a, b]);
+        assert!(message(&first).dropped.is_empty());
+        assert!(state.update(&[a, b], to).is_none());
+
+        let second = state.update(&[b, c], to).unwrap();
+        assert_eq!(parse_added(&second).unwrap(), [c]);
+        assert_eq!(parse_compact_peers(&message(&second).dropped), [a.address]);
+        assert!(state.update(&[b, c], to).is_none());
+    }
+
+    #[test]
+    fn sends_at_most_fifty_peers_at_a_time() {
+        let mut state = PexState::default();
+        let to = TO.parse().unwrap();
+        let connected: Vec<_> = (1..=120)
+            .map(|i| peer(&format!("10.1.0.{i}:6881"), 0))
+            .collect();
+        let sizes: Vec<_> = std::iter::from_fn(|| state.update(&connected, to))
+            .map(|payload| parse_added(&payload).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [50, 50, 20]);
+
+        let payload = state.update(&[], to).unwrap();
+        assert_eq!(message(&payload).dropped.len(), 50 * 6);
+    }
+
+    #[test]
+    fn encodes_one_flag_byte_per_added_peer() {
+        let mut state = PexState::default();
+        let peers = [
+            peer("10.0.0.2:1", PEX_SEED | PEX_REACHABLE),
+            peer("10.0.0.3:2", 0),
+        ];
+        let payload = state.update(&peers, TO.parse().unwrap()).unwrap();
+        let message = message(&payload);
+        assert_eq!(message.added_f.as_slice(), [PEX_SEED | PEX_REACHABLE, 0]);
+        assert_eq!(
+            message.added.as_slice(),
+            [10, 0, 0, 2, 0, 1, 10, 0, 0, 3, 0, 2]
+        );
+        assert!(message.added6.is_none() && message.added6_f.is_none());
+    }
+
+    #[test]
+    fn sends_ipv6_peers_in_their_own_keys() {
+        let mut state = PexState::default();
+        let v4 = peer("10.0.0.2:1", 0);
+        let v6 = peer("[2001:db8::1]:6881", PEX_SEED);
+        let to = TO.parse().unwrap();
+        let payload = state.update(&[v4, v6], to).unwrap();
+        let parsed = message(&payload);
+        assert_eq!(parsed.added.len(), 6);
+        assert_eq!(bytes(&parsed.added6).len(), 18);
+        assert_eq!(bytes(&parsed.added6_f), [PEX_SEED]);
+        assert_eq!(parse_added(&payload).unwrap(), [v4, v6]);
+
+        let payload = state.update(&[v4], to).unwrap();
+        assert_eq!(
+            parse_compact_peers6(bytes(&message(&payload).dropped6)),
+            [v6.address]
+        );
+    }
+
+    #[test]
+    fn parses_peers_missing_flags_and_skips_dropped_ones() {
+        let added = compact(["10.0.0.2:1", "10.0.0.3:2", "10.0.0.4:0"].map(|a| a.parse().unwrap()));
+        let dropped = compact(["10.0.0.3:2".parse().unwrap()]);
+        let payload = serde_bencode::to_bytes(&PexMessage {
+            added,
+            added_f: ByteBuf::from(vec![PEX_SEED]),
+            dropped,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            parse_added(&payload).unwrap(),
+            [peer("10.0.0.2:1", PEX_SEED)]
+        );
+    }
+
+    #[test]
+    fn rejects_compact_lists_of_the_wrong_length() {
+        let payload = |message: PexMessage| serde_bencode::to_bytes(&message).unwrap();
+        assert!(parse_added(&payload(PexMessage {
+            added: ByteBuf::from(vec![0; 7]),
+            ..Default::default()
+        }))
+        .is_err());
+        assert!(parse_added(&payload(PexMessage {
+            dropped: ByteBuf::from(vec![0; 5]),
+            ..Default::default()
+        }))
+        .is_err());
+        assert!(parse_added(&payload(PexMessage {
+            added6: Some(ByteBuf::from(vec![0; 17])),
+            ..Default::default()
+        }))
+        .is_err());
+        assert!(parse_added(b"d5:added3:abce").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use bitvec::prelude::*;
+use rand::{seq::IteratorRandom, Rng};
+
+// Pieces picked at random before switching to rarest-first, so we quickly have
+// something complete to offer other peers.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+// Decides which piece to download next from which peer. Peers are identified by
+// the index `add_peer` hands out; the picker only sees what they announced in
+// BITFIELD and HAVE messages, so it works without any connection.
+pub struct PiecePicker {
+    // Number of connected peers that have each piece.
+    availability: Vec<u32>,
+    have: BitVec<u8, Msb0>,
+    in_progress: BitVec<u8, Msb0>,
+    // `None` once the peer is gone.
+    peers: Vec<Option<BitVec<u8, Msb0>>>,
+    sequential: bool,
+    random_first: usize,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            availability: vec![0; num_pieces],
+            have: bitvec![u8, Msb0; 0; num_pieces],
+            in_progress: bitvec![u8, Msb0; 0; num_pieces],
+            peers: Vec::new(),
+            sequential: false,
+            random_first: RANDOM_FIRST_PIECES,
+        }
+    }
+
+    // Picks the lowest missing piece instead, e.g. for streaming.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn set_random_first(&mut self, pieces: usize) {
+        self.random_first = pieces;
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn have(&self, piece: usize) -> bool {
+        self.have[piece]
+    }
+
+    pub fn have_count(&self) -> usize {
+        self.have.count_ones()
+    }
+
+    // Our BITFIELD payload.
+    pub fn bitfield(&self) -> Vec<u8> {
+        self.have.as_raw_slice().to_vec()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.all()
+    }
+
+    // Every missing piece is already being downloaded, so the only way to
+    // speed things up is to ask more peers for the same pieces.
+    pub fn is_endgame(&self) -> bool {
+        !self.is_complete() && (0..self.num_pieces()).all(|p| self.have[p] || self.in_progress[p])
+    }
+
+    pub fn has_piece(&self, peer: usize, piece: usize) -> bool {
+        matches!(self.peers.get(peer), Some(Some(pieces)) if pieces[piece])
+    }
+
+    pub fn availability(&self, piece: usize) -> u32 {
+        self.availability[piece]
+    }
+
+    // Records a piece that passed its hash check.
+    pub fn mark_have(&mut self, piece: usize) {
+        self.have.set(piece, true);
+        self.in_progress.set(piece, false);
+    }
+
+    // Makes a picked piece available again, e.g. after its download failed.
+    pub fn abort(&mut self, piece: usize) {
+        self.in_progress.set(piece, false);
+    }
+
+    pub fn add_peer(&mut self) -> usize {
+        self.peers
+            .push(Some(bitvec![u8, Msb0; 0; self.num_pieces()]));
+        self.peers.len() - 1
+    }
+
+    // Forgets a disconnected peer and what it contributed to availability.
+    pub fn remove_peer(&mut self, peer: usize) {
+        if let Some(pieces) = self.peers.get_mut(peer).and_then(Option::take) {
+            for piece in pieces.iter_ones() {
+                self.availability[piece] -= 1;
+            }
+        }
+    }
+
+    // A piece the peer announced, from its BITFIELD or a HAVE. Out of range
+    // indexes and repeats are ignored.
+    pub fn peer_has(&mut self, peer: usize, piece: usize) {
+        let Some(Some(pieces)) = self.peers.get_mut(peer) else {
+            return;
+        };
+        if piece < pieces.len() && !pieces[piece] {
+            pieces.set(piece, true);
+            self.availability[piece] += 1;
+        }
+    }
+
+    pub fn peer_bitfield(&mut self, peer: usize, bitfield: &[u8]) {
+        for piece in BitSlice::<u8, Msb0>::from_slice(bitfield).iter_ones() {
+            self.peer_has(peer, piece);
+        }
+    }
+
+    // Whether the peer has anything we are still missing.
+    pub fn is_interesting(&self, peer: usize) -> bool {
+        self.candidates(peer).next().is_some() || self.in_progress_from(peer)
+    }
+
+    // Chooses a missing piece the peer has that nobody is downloading yet and
+    // marks it as in progress.
+    pub fn pick(&mut self, peer: usize, rng: &mut impl Rng) -> Option<usize> {
+        let piece = if self.sequential {
+            self.candidates(peer).next()
+        } else if self.have_count() < self.random_first {
+            self.candidates(peer).choose(rng)
+        } else {
+            self.rarest(peer, rng)
+        }?;
+        self.in_progress.set(piece, true);
+        Some(piece)
+    }
+
+    fn candidates(&self, peer: usize) -> impl Iterator<Item = usize> + '_ {
+        let pieces = self.peers.get(peer).and_then(Option::as_ref);
+        pieces
+            .into_iter()
+            .flat_map(|pieces| pieces.iter_ones())
+            .filter(|piece| !self.have[*piece] && !self.in_progress[*piece])
+    }
+
+    fn in_progress_from(&self, peer: usize) -> bool {
+        let Some(Some(pieces)) = self.peers.get(peer) else {
+            return false;
+        };
+        pieces
+            .iter_ones()
+            .any(|piece| self.in_progress[piece] && !self.have[piece])
+    }
+
+    // Lowest availability wins; ties are broken uniformly at random so peers
+    // that start at the same time don't all go for the same piece.
+    fn rarest(&self, peer: usize, rng: &mut impl Rng) -> Option<usize> {
+        let mut best = None;
+        let mut ties = 0;
+        for piece in self.candidates(peer) {
+            let availability = self.availability[piece];
+            match best {
+                Some((_, min)) if availability > min => {}
+                Some((_, min)) if availability == min => {
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        best = Some((piece, availability));
+                    }
+                }
+                _ => {
+                    best = Some((piece, availability));
+                    ties = 1;
+                }
+            }
+        }
+        best.map(|(piece, _)| piece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // Peer 0 has every piece; piece `p` is also held by `extra[p]` other peers.
+    fn picker(extra: &[usize]) -> PiecePicker {
+        let mut picker = PiecePicker::new(extra.len());
+        let all = picker.add_peer();
+        for piece in 0..extra.len() {
+            picker.peer_has(all, piece);
+        }
+        for (piece, &count) in extra.iter().enumerate() {
+            for _ in 0..count {
+                let peer = picker.add_peer();
+                picker.peer_has(peer, piece);
+            }
+        }
+        picker
+    }
+
+    #[test]
+    fn picks_rarest_first() {
+        let mut picker = picker(&[3, 1, 0, 2]);
+        picker.set_random_first(0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let order: Vec<_> = std::iter::from_fn(|| picker.pick(0, &mut rng)).collect();
+        assert_eq!(order, [2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn breaks_ties_at_random() {
+        let mut seen = [false; 4];
+        for seed in 0..64 {
+            let mut picker = picker(&[1, 0, 0, 0]);
+            picker.set_random_first(0);
+            let piece = picker.pick(0, &mut StdRng::seed_from_u64(seed)).unwrap();
+            seen[piece] = true;
+        }
+        assert_eq!(seen, [false, true, true, true]);
+    }
+
+    #[test]
+    fn picks_in_order_when_sequential() {
+        let mut picker = picker(&[3, 1, 0, 2]);
+        picker.set_sequential(true);
+        let mut rng = StdRng::seed_from_u64(1);
+        let order: Vec<_> = std::iter::from_fn(|| picker.pick(0, &mut rng)).collect();
+        assert_eq!(order, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn picks_the_first_pieces_at_random() {
+        let extra = [0, 1, 1, 1, 1, 1, 1, 1];
+        let mut seen = [false; 8];
+        for seed in 0..64 {
+            let mut picker = picker(&extra);
+            picker.set_random_first(1);
+            let mut rng = StdRng::seed_from_u64(seed);
+            let first = picker.pick(0, &mut rng).unwrap();
+            seen[first] = true;
+            // One verified piece is enough to switch to rarest-first.
+            picker.mark_have(first);
+            if first != 0 {
+                assert_eq!(picker.pick(0, &mut rng), Some(0));
+            }
+        }
+        assert!(seen.iter().filter(|seen| **seen).count() > 1);
+    }
+}
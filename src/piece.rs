@@ -0,0 +1,153 @@
+use bitvec::prelude::*;
+use std::sync::Mutex as StdMutex;
+use tokio::sync::{futures::Notified, Notify};
+
+use crate::message::BlockRequest;
+use crate::peer::BLOCK_SIZE;
+
+// A piece being assembled from its blocks. In endgame mode several peers fill
+// the same buffer, each skipping and cancelling blocks another peer delivered.
+pub struct PieceBuffer {
+    index: u32,
+    len: u32,
+    blocks: StdMutex<Blocks>,
+    // Signalled whenever a new block is stored.
+    arrived: Notify,
+}
+
+struct Blocks {
+    data: Vec<u8>,
+    received: BitVec<u8, Msb0>,
+}
+
+impl PieceBuffer {
+    pub fn new(index: u32, len: u32) -> Self {
+        let num_blocks = len.div_ceil(BLOCK_SIZE) as usize;
+        Self {
+            index,
+            len,
+            blocks: StdMutex::new(Blocks {
+                data: vec![0; len as usize],
+                received: bitvec![u8, Msb0; 0; num_blocks],
+            }),
+            arrived: Notify::new(),
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn missing(&self) -> Vec<BlockRequest> {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .received
+            .iter_zeros()
+            .map(|block| {
+                let begin = block as u32 * BLOCK_SIZE;
+                BlockRequest {
+                    index: self.index,
+                    begin,
+                    length: BLOCK_SIZE.min(self.len - begin),
+                }
+            })
+            .collect()
+    }
+
+    pub fn is_received(&self, begin: u32) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .received
+            .get((begin / BLOCK_SIZE) as usize)
+            .is_some_and(|received| *received)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.lock().unwrap().received.all()
+    }
+
+    // Stores a block, returning false if we already had it.
+    pub fn add(&self, begin: u32, data: &[u8]) -> anyhow::Result<bool> {
+        anyhow::ensure!(
+            begin.is_multiple_of(BLOCK_SIZE) && begin < self.len,
+            "block at {} is not one we requested",
+            begin
+        );
+        let expected = BLOCK_SIZE.min(self.len - begin) as usize;
+        anyhow::ensure!(
+            data.len() == expected,
+            "block at {} has {} bytes, expected {}",
+            begin,
+            data.len(),
+            expected
+        );
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = (begin / BLOCK_SIZE) as usize;
+        if blocks.received[block] {
+            return Ok(false);
+        }
+        let start = begin as usize;
+        blocks.data[start..start + data.len()].copy_from_slice(data);
+        blocks.received.set(block, true);
+        drop(blocks);
+        self.arrived.notify_waiters();
+        Ok(true)
+    }
+
+    pub fn arrived(&self) -> Notified<'_> {
+        self.arrived.notified()
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        self.blocks.lock().unwrap().data.clone()
+    }
+
+    // Throws away every block, e.g. after the piece failed its hash check.
+    pub fn reset(&self) {
+        self.blocks.lock().unwrap().received.fill(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_missing_blocks_with_a_short_last_one() {
+        let piece = PieceBuffer::new(4, 2 * BLOCK_SIZE + 10);
+        piece.add(BLOCK_SIZE, &[0; BLOCK_SIZE as usize]).unwrap();
+        let missing: Vec<_> = piece
+            .missing()
+            .iter()
+            .map(|b| (b.begin, b.length))
+            .collect();
+        assert_eq!(missing, [(0, BLOCK_SIZE), (2 * BLOCK_SIZE, 10)]);
+        assert!(piece.missing().iter().all(|b| b.index == 4));
+    }
+
+    #[test]
+    fn keeps_the_first_copy_of_a_block() {
+        let piece = PieceBuffer::new(0, BLOCK_SIZE + 10);
+        assert!(piece.add(BLOCK_SIZE, &[1; 10]).unwrap());
+        assert!(!piece.add(BLOCK_SIZE, &[2; 10]).unwrap());
+        assert!(piece.is_received(BLOCK_SIZE));
+        assert!(!piece.is_complete());
+        assert!(piece.add(0, &[3; BLOCK_SIZE as usize]).unwrap());
+        assert!(piece.is_complete());
+        assert_eq!(piece.data()[BLOCK_SIZE as usize..], [1; 10]);
+
+        piece.reset();
+        assert_eq!(piece.missing().len(), 2);
+        assert!(piece.add(BLOCK_SIZE, &[2; 10]).unwrap());
+    }
+
+    #[test]
+    fn rejects_blocks_we_did_not_ask_for() {
+        let piece = PieceBuffer::new(0, BLOCK_SIZE + 10);
+        assert!(piece.add(1, &[0; 10]).is_err());
+        assert!(piece.add(2 * BLOCK_SIZE, &[0; 10]).is_err());
+        assert!(piece.add(BLOCK_SIZE, &[0; 11]).is_err());
+        assert!(piece.add(0, &[0; 10]).is_err());
+        assert!(!piece.is_received(0));
+    }
+}
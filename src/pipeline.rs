@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use crate::peer::BLOCK_SIZE;
+use crate::rate::RateMeter;
+
+pub const MIN_REQUESTS: usize = 5;
+pub const MAX_REQUESTS: usize = 250;
+// Keep this much of a peer's measured throughput in flight, so the pipe stays
+// full for a round trip even when the peer is fast.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+// How many block requests may be outstanding on one connection. The depth
+// follows the peer's download rate, bounded by our limits and by the `reqq`
+// the peer advertised in its extension handshake.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    min: usize,
+    max: usize,
+    peer_limit: Option<usize>,
+    depth: usize,
+    outstanding: usize,
+    received: RateMeter,
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        Self::new(MIN_REQUESTS, MAX_REQUESTS)
+    }
+}
+
+impl RequestWindow {
+    pub fn new(min: usize, max: usize) -> Self {
+        let max = max.max(1);
+        let min = min.clamp(1, max);
+        Self {
+            min,
+            max,
+            peer_limit: None,
+            depth: min,
+            outstanding: 0,
+            received: RateMeter::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, min: usize, max: usize) {
+        let window = Self::new(min, max);
+        self.min = window.min;
+        self.max = window.max;
+        self.depth = self.depth.clamp(self.min, self.max);
+    }
+
+    pub fn set_peer_limit(&mut self, reqq: usize) {
+        self.peer_limit = Some(reqq.max(1));
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn limit(&self) -> usize {
+        match self.peer_limit {
+            Some(reqq) => self.depth.min(reqq),
+            None => self.depth,
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    pub fn rate(&self, now: Instant) -> f64 {
+        self.received.rate(now)
+    }
+
+    // Takes a slot for a new request if the window has room.
+    pub fn try_acquire(&mut self) -> bool {
+        if self.outstanding >= self.limit() {
+            return false;
+        }
+        self.outstanding += 1;
+        true
+    }
+
+    // Gives back a slot whose request was cancelled or lost.
+    pub fn release(&mut self) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+    }
+
+    // Accounts for a block that arrived and resizes the window once per interval.
+    pub fn on_block(&mut self, len: usize, now: Instant) {
+        self.release();
+        if !self.received.add(len, now) {
+            return;
+        }
+        let depth = self.received.rate(now) * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64;
+        self.depth = (depth.ceil() as usize).clamp(self.min, self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    // Feeds `blocks` blocks in one interval and closes it, returning when it closed.
+    fn second_of(window: &mut RequestWindow, start: Instant, blocks: usize) -> Instant {
+        for _ in 0..blocks {
+            window.on_block(BLOCK, start);
+        }
+        let end = start + Duration::from_secs(1);
+        window.on_block(0, end);
+        end
+    }
+
+    #[test]
+    fn follows_the_download_rate() {
+        let mut window = RequestWindow::new(5, 1000);
+        assert_eq!(window.limit(), 5);
+        let now = second_of(&mut window, Instant::now(), 100);
+        // 100 blocks a second, three seconds of them in flight.
+        assert!((295..=300).contains(&window.limit()), "{}", window.limit());
+
+        second_of(&mut window, now, 20);
+        assert!((59..=60).contains(&window.limit()), "{}", window.limit());
+    }
+
+    #[test]
+    fn stays_within_its_limits() {
+        let mut window = RequestWindow::new(5, 50);
+        let now = second_of(&mut window, Instant::now(), 100);
+        assert_eq!(window.limit(), 50);
+        second_of(&mut window, now, 0);
+        assert_eq!(window.limit(), 5);
+
+        assert_eq!(RequestWindow::new(0, 0).limit(), 1);
+        assert_eq!(RequestWindow::new(10, 5).limit(), 5);
+        window.set_limits(8, 20);
+        assert_eq!(window.limit(), 8);
+    }
+
+    #[test]
+    fn never_exceeds_the_peers_reqq() {
+        let mut window = RequestWindow::new(5, 1000);
+        second_of(&mut window, Instant::now(), 100);
+        window.set_peer_limit(20);
+        assert_eq!(window.limit(), 20);
+        assert_eq!((0..30).filter(|_| window.try_acquire()).count(), 20);
+        assert_eq!(window.outstanding(), 20);
+        window.release();
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+
+        window.set_peer_limit(0);
+        assert_eq!(window.limit(), 1);
+    }
+}
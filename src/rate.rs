@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes per second, averaged over whole intervals so single blocks don't make
+// it jump around.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    bytes: u64,
+    since: Instant,
+    rate: f64,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            bytes: 0,
+            since: now,
+            rate: 0.0,
+        }
+    }
+
+    // Counts transferred bytes. Returns true when this closed an interval and
+    // the rate was updated.
+    pub fn add(&mut self, bytes: usize, now: Instant) -> bool {
+        self.bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < RATE_INTERVAL {
+            return false;
+        }
+        self.rate = self.bytes as f64 / elapsed.as_secs_f64();
+        self.bytes = 0;
+        self.since = now;
+        true
+    }
+
+    // The rate of the last interval, or, once nothing has been added for a
+    // while, the average since then, so a peer that went quiet decays to zero.
+    pub fn rate(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < RATE_INTERVAL * 2 {
+            return self.rate;
+        }
+        self.bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_over_whole_intervals() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        assert!(!meter.add(1000, start + Duration::from_millis(500)));
+        assert_eq!(meter.rate(start + Duration::from_millis(500)), 0.0);
+        assert!(meter.add(1000, start + Duration::from_secs(2)));
+        assert_eq!(meter.rate(start + Duration::from_secs(2)), 1000.0);
+    }
+
+    #[test]
+    fn decays_once_the_transfer_stops() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        meter.add(4000, start + Duration::from_secs(1));
+        assert_eq!(meter.rate(start + Duration::from_secs(2)), 4000.0);
+        meter.add(1000, start + Duration::from_millis(1500));
+        assert_eq!(meter.rate(start + Duration::from_secs(5)), 250.0);
+    }
+}
@@ -0,0 +1,171 @@
+use bitvec::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+
+use crate::{download::sanitize_path_component, storage::Storage, torrent::Info};
+
+// Size and modification time of one file backing a `Storage`, used to tell
+// whether the data changed since the resume file was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: u64,
+    pub mtime: u64, // nanoseconds since the Unix epoch
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResumeFile {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
+    files: Vec<FileStamp>,
+}
+
+pub struct Resume {
+    path: Option<PathBuf>,
+    info_hash: [u8; 20],
+    have: BitVec<u8, Msb0>,
+}
+
+impl Resume {
+    // Starts with no pieces and never touches disk.
+    pub fn empty(info: &Info) -> Self {
+        Self {
+            path: None,
+            info_hash: info.hash(),
+            have: bitvec![u8, Msb0; 0; info.num_pieces()],
+        }
+    }
+
+    // Trusts the sidecar at `path` if it matches the torrent and the files are
+    // unchanged since it was written; otherwise hash-checks whatever `storage`
+    // did not just create.
+    pub fn load(path: PathBuf, info: &Info, storage: &dyn Storage) -> anyhow::Result<Self> {
+        let info_hash = info.hash();
+        let num_pieces = info.num_pieces();
+        let saved = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_bencode::from_bytes::<ResumeFile>(&bytes).ok())
+            .filter(|saved| saved.info_hash == info_hash)
+            .filter(|saved| saved.bitfield.len() == num_pieces.div_ceil(8));
+
+        let mut resume = Self {
+            path: Some(path),
+            info_hash,
+            have: bitvec![u8, Msb0; 0; num_pieces],
+        };
+        match saved {
+            Some(saved) if saved.files == storage.file_stamps()? => {
+                let mut have = BitVec::<u8, Msb0>::from_vec(saved.bitfield);
+                have.truncate(num_pieces);
+                resume.have = have;
+            }
+            _ => {
+                resume.have = recheck(info, storage);
+                resume.save(storage)?;
+            }
+        }
+        Ok(resume)
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        self.have[piece]
+    }
+
+    pub fn count(&self) -> usize {
+        self.have.count_ones()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.all()
+    }
+
+    pub fn mark_done(&mut self, piece: usize, storage: &dyn Storage) -> anyhow::Result<()> {
+        self.have.set(piece, true);
+        self.save(storage)
+    }
+
+    fn save(&self, storage: &dyn Storage) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = ResumeFile {
+            info_hash: self.info_hash.to_vec(),
+            bitfield: self.have.as_raw_slice().to_vec(),
+            files: storage.file_stamps()?,
+        };
+        // Write-then-rename so a crash never leaves a truncated sidecar behind.
+        let tmp = path.with_extension("resume.tmp");
+        std::fs::write(&tmp, serde_bencode::to_bytes(&file)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+// `<output>.resume` next to a single file, `<output>/<name>.resume` next to a
+// multi-file torrent's directory.
+pub fn resume_path(output: &Path, info: &Info) -> PathBuf {
+    if info.is_multi_file() {
+        output.join(format!("{}.resume", sanitize_path_component(info.name())))
+    } else {
+        let mut name = output.as_os_str().to_owned();
+        name.push(".resume");
+        PathBuf::from(name)
+    }
+}
+
+pub fn recheck(info: &Info, storage: &dyn Storage) -> BitVec<u8, Msb0> {
+    let mut have = bitvec![u8, Msb0; 0; info.num_pieces()];
+    let mut buf = Vec::new();
+    for (piece, hash) in info.pieces().iter().enumerate() {
+        let (offset, len) = (info.piece_offset(piece), info.piece_len(piece));
+        if storage.is_fresh(offset, len as u64) {
+            continue;
+        }
+        buf.resize(len as usize, 0);
+        if storage.read(offset, &mut buf).is_ok() && *hash == *Sha1::digest(&buf) {
+            have.set(piece, true);
+        }
+    }
+    have
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::BencodeValue, storage::FileStorage};
+
+    // Two 16 KiB pieces of zeros, so a zero-filled file verifies.
+    fn zeros() -> Info {
+        let hash = Sha1::digest([0; 16384]).to_vec();
+        let dict = BencodeValue::Dict(vec![
+            (b"length".to_vec(), BencodeValue::Int(32768)),
+            (b"name".to_vec(), BencodeValue::Bytes(b"zeros".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(16384)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(hash.repeat(2))),
+        ]);
+        Info::from_bytes(&dict.encode()).unwrap()
+    }
+
+    #[test]
+    fn skips_checking_files_it_just_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("zeros");
+        let info = zeros();
+        let storage = FileStorage::new(&output, &info).unwrap();
+        let resume = Resume::load(resume_path(&output, &info), &info, &storage).unwrap();
+        assert_eq!(resume.count(), 0);
+    }
+
+    #[test]
+    fn checks_files_already_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("zeros");
+        std::fs::write(&output, [0; 32768]).unwrap();
+        let info = zeros();
+        let storage = FileStorage::new(&output, &info).unwrap();
+        let resume = Resume::load(resume_path(&output, &info), &info, &storage).unwrap();
+        assert!(resume.is_complete());
+    }
+}
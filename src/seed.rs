@@ -0,0 +1,274 @@
+use bitvec::prelude::*;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+use crate::{
+    choker::{Choker, RECHOKE_INTERVAL},
+    extension::ExtensionRegistry,
+    message::{BlockRequest, Message},
+    metadata::MetadataExtension,
+    peer::Peer,
+    pex::{PexExtension, PexPeer, PexState, PEX_INTERVAL, UT_PEX},
+    storage::Storage,
+    torrent::Info,
+};
+
+pub const UPLOAD_SLOTS: usize = 4;
+// Larger requests are refused, as most clients do; 16 KiB is the norm.
+const MAX_REQUEST_LEN: u32 = 128 * 1024;
+
+// A torrent we serve, with the pieces that passed their hash check on disk.
+struct SeedTorrent {
+    info: Info,
+    storage: Arc<dyn Storage>,
+    have: BitVec<u8, Msb0>,
+    peers: StdMutex<HashMap<SocketAddr, Peer>>,
+    choker: StdMutex<Choker<SocketAddr>>,
+    extensions: Arc<ExtensionRegistry>,
+    // What each peer has heard from us over PEX.
+    pex: StdMutex<HashMap<SocketAddr, PexState>>,
+    // Bytes of PIECE payload sent to all peers, for the tracker.
+    uploaded: AtomicU64,
+}
+
+impl SeedTorrent {
+    fn read_block(&self, block: BlockRequest) -> anyhow::Result<Vec<u8>> {
+        let piece = block.index as usize;
+        anyhow::ensure!(
+            piece < self.info.num_pieces() && self.have[piece],
+            "requested piece {} we don't have",
+            piece
+        );
+        read_block(&self.info, self.storage.as_ref(), block)
+    }
+
+    fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    // Reranks the peers by how fast we upload to them.
+    async fn rechoke(&self) {
+        let peers = self.peers();
+        let stats: Vec<_> = peers
+            .iter()
+            .map(|peer| peer.choker_stats(peer.address))
+            .collect();
+        let unchoked = self
+            .choker
+            .lock()
+            .unwrap()
+            .rechoke(&stats, true, &mut rand::thread_rng());
+        for mut peer in peers {
+            // A send only fails once the peer is gone, and then it's no longer ours to choke.
+            let _ = peer.set_choking(!unchoked.contains(&peer.address)).await;
+        }
+    }
+
+    // Tells the peers about each other. Only their listen ports are of use to
+    // others, so peers that didn't send one are left out.
+    async fn send_pex(&self) {
+        let peers = self.peers();
+        let listening: Vec<_> = peers
+            .iter()
+            .filter_map(|peer| {
+                let address = SocketAddr::new(peer.address.ip(), peer.listen_port()?);
+                Some(PexPeer { address, flags: 0 })
+            })
+            .collect();
+        for mut peer in peers {
+            if peer.extensions().and_then(|h| h.id(UT_PEX)).is_none() {
+                continue;
+            }
+            let own = SocketAddr::new(peer.address.ip(), peer.listen_port().unwrap_or(0));
+            let payload = self
+                .pex
+                .lock()
+                .unwrap()
+                .entry(peer.address)
+                .or_default()
+                .update(&listening, own);
+            if let Some(payload) = payload {
+                let _ = peer.send_extension(UT_PEX, payload).await;
+            }
+        }
+    }
+
+    // Between rechokes a newly interested peer is only unchoked into a free slot.
+    async fn unchoke_if_free(&self, peer: &mut Peer) -> anyhow::Result<()> {
+        let unchoked = self
+            .peers()
+            .iter()
+            .filter(|peer| !peer.choke_state().am_choking)
+            .count();
+        if unchoked < self.choker.lock().unwrap().slots() {
+            peer.set_choking(false).await?;
+        }
+        Ok(())
+    }
+}
+
+// Reads a block of a piece we have, after checking the request is sane.
+pub(crate) fn read_block(
+    info: &Info,
+    storage: &dyn Storage,
+    block: BlockRequest,
+) -> anyhow::Result<Vec<u8>> {
+    let piece = block.index as usize;
+    anyhow::ensure!(
+        piece < info.num_pieces()
+            && block.length <= MAX_REQUEST_LEN
+            && block.begin as u64 + block.length as u64 <= info.piece_len(piece) as u64,
+        "invalid request {:?}",
+        block
+    );
+    let mut data = vec![0; block.length as usize];
+    storage.read(info.piece_offset(piece) + block.begin as u64, &mut data)?;
+    Ok(data)
+}
+
+// Accepts inbound peers for any number of torrents and serves them pieces.
+pub struct Seeder {
+    torrents: HashMap<[u8; 20], Arc<SeedTorrent>>,
+    upload_slots: usize,
+    // Told to peers in the extension handshake.
+    listen_port: Option<u16>,
+}
+
+impl Seeder {
+    pub fn new(upload_slots: usize) -> Self {
+        Self {
+            torrents: HashMap::new(),
+            upload_slots,
+            listen_port: None,
+        }
+    }
+
+    // The port of the listener `run` will get; set it before adding torrents.
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    // `have` marks the pieces of `storage` that were verified and may be served.
+    pub fn add(&mut self, info: Info, storage: Arc<dyn Storage>, have: BitVec<u8, Msb0>) {
+        let mut extensions = ExtensionRegistry::new()
+            .with_handler(MetadataExtension::new(Some(info.raw().to_vec())));
+        // We have nowhere to put the peers others tell us about, but they
+        // can still learn about each other from us.
+        if !info.is_private() {
+            extensions = extensions.with_handler(PexExtension::new(None));
+        }
+        if let Some(port) = self.listen_port {
+            extensions = extensions.with_listen_port(port);
+        }
+        let torrent = SeedTorrent {
+            info,
+            storage,
+            have,
+            peers: StdMutex::default(),
+            choker: StdMutex::new(Choker::new(self.upload_slots)),
+            extensions: Arc::new(extensions),
+            pex: StdMutex::default(),
+            uploaded: AtomicU64::new(0),
+        };
+        self.torrents.insert(torrent.info.hash(), Arc::new(torrent));
+    }
+
+    // Bytes uploaded so far for the torrent with this info-hash.
+    pub fn uploaded(&self, info_hash: &[u8; 20]) -> u64 {
+        self.torrents
+            .get(info_hash)
+            .map_or(0, |torrent| torrent.uploaded.load(Ordering::Relaxed))
+    }
+
+    // Serves peers connecting to `listener` until accepting fails.
+    pub async fn run(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        let seeder = self;
+        // Stopped when we return.
+        let mut timers = JoinSet::new();
+        for torrent in seeder.torrents.values() {
+            timers.spawn({
+                let torrent = torrent.clone();
+                async move {
+                    let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        torrent.rechoke().await;
+                    }
+                }
+            });
+            if !torrent.info.is_private() {
+                let torrent = torrent.clone();
+                timers.spawn(async move {
+                    let mut interval = tokio::time::interval(PEX_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        torrent.send_pex().await;
+                    }
+                });
+            }
+        }
+        loop {
+            let (stream, address) = listener.accept().await?;
+            // IPv4 peers on a dual-stack listener show up as ::ffff:a.b.c.d.
+            let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+            let seeder = seeder.clone();
+            tokio::spawn(async move {
+                if let Err(e) = seeder.serve(stream, address).await {
+                    eprintln!("{} -> {}", address, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, stream: TcpStream, address: SocketAddr) -> anyhow::Result<()> {
+        let (mut peer, info_hash) =
+            Peer::accept(stream, address, |hash| self.torrents.contains_key(hash)).await?;
+        let torrent = self.torrents[&info_hash].clone();
+        println!("Peer {} connected", address);
+
+        torrent.peers.lock().unwrap().insert(address, peer.clone());
+        let result = Self::serve_torrent(&mut peer, &torrent).await;
+        torrent.peers.lock().unwrap().remove(&address);
+        torrent.pex.lock().unwrap().remove(&address);
+        result
+    }
+
+    async fn serve_torrent(peer: &mut Peer, torrent: &SeedTorrent) -> anyhow::Result<()> {
+        let bitfield = torrent.have.as_raw_slice().to_vec();
+        peer.send(Message::Bitfield(bitfield)).await?;
+        if peer.supports_extension {
+            peer.set_extensions(torrent.extensions.clone());
+            peer.send_extension_handshake().await?;
+        }
+        loop {
+            match peer.recv().await? {
+                Message::Interested => torrent.unchoke_if_free(peer).await?,
+                Message::NotInterested => peer.set_choking(true).await?,
+                Message::Request(block) => {
+                    // BEP 3 has us drop requests from peers we choke.
+                    if peer.choke_state().am_choking {
+                        continue;
+                    }
+                    let data = torrent.read_block(block)?;
+                    let len = data.len() as u64;
+                    peer.send_block(block.index, block.begin, data).await?;
+                    torrent.uploaded.fetch_add(len, Ordering::Relaxed);
+                }
+                // Requests are answered as they arrive, so there's nothing
+                // queued for a CANCEL to take back.
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::Context;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::{download::Downloaded, resume::FileStamp, torrent::Info};
+
+// Offsets are positions in the torrent's concatenated payload, so callers
+// never need to know how the bytes are laid out on disk.
+pub trait Storage: Send + Sync {
+    fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()>;
+    fn read(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()>;
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn file_stamps(&self) -> anyhow::Result<Vec<FileStamp>> {
+        Ok(vec![])
+    }
+    // True when all of `offset..offset + len` lies in files this storage just
+    // created, which hold nothing but zeros and are not worth hash-checking.
+    fn is_fresh(&self, _offset: u64, _len: u64) -> bool {
+        false
+    }
+}
+
+pub struct FileStorage {
+    files: Vec<StorageFile>,
+}
+
+struct StorageFile {
+    offset: u64,
+    len: u64,
+    // `None` only for files `open_partial` could not open.
+    file: Mutex<Option<File>>,
+    // How much of the file is there to read; short of `len` only for `open_partial`.
+    available: u64,
+    created: bool,
+}
+
+impl FileStorage {
+    // Single-file torrents are written to `output` itself, multi-file torrents
+    // to `output/<name>/<path...>`.
+    pub fn new(output: &Path, info: &Info) -> anyhow::Result<Self> {
+        let downloaded = Downloaded::new(info);
+        let mut files = Vec::new();
+        for file in &downloaded {
+            let path = file.output_path(output);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let created = !path.exists();
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            // Leave the mtime alone when the size is already right, so resume data stays valid.
+            if handle.metadata()?.len() != file.len() {
+                handle.set_len(file.len())?;
+            }
+            files.push(StorageFile {
+                offset: file.offset(),
+                len: file.len(),
+                file: Mutex::new(Some(handle)),
+                available: file.len(),
+                created,
+            });
+        }
+        Ok(Self { files })
+    }
+
+    // Opens existing data read-only, e.g. for seeding. Missing files and files
+    // of the wrong size are errors; nothing is created or resized.
+    pub fn open(output: &Path, info: &Info) -> anyhow::Result<Self> {
+        let downloaded = Downloaded::new(info);
+        let mut files = Vec::new();
+        for file in &downloaded {
+            let path = file.output_path(output);
+            let handle =
+                File::open(&path).with_context(|| format!("cannot open {}", path.display()))?;
+            let len = handle.metadata()?.len();
+            anyhow::ensure!(
+                len == file.len(),
+                "{} is {} bytes, expected {}",
+                path.display(),
+                len,
+                file.len()
+            );
+            files.push(StorageFile {
+                offset: file.offset(),
+                len: file.len(),
+                file: Mutex::new(Some(handle)),
+                available: file.len(),
+                created: false,
+            });
+        }
+        Ok(Self { files })
+    }
+
+    // Like `open`, but for checking data that may be incomplete: missing and
+    // short files are tolerated, and `is_available` tells which ranges can be read.
+    pub fn open_partial(output: &Path, info: &Info) -> anyhow::Result<Self> {
+        let downloaded = Downloaded::new(info);
+        let mut files = Vec::new();
+        for file in &downloaded {
+            let (handle, available) = match File::open(file.output_path(output)) {
+                Ok(handle) => {
+                    let metadata = handle.metadata()?;
+                    // Anything but a regular file counts as present; reading it fails.
+                    let available = if metadata.is_file() {
+                        metadata.len().min(file.len())
+                    } else {
+                        file.len()
+                    };
+                    (Some(handle), available)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, 0),
+                Err(_) => (None, file.len()),
+            };
+            files.push(StorageFile {
+                offset: file.offset(),
+                len: file.len(),
+                file: Mutex::new(handle),
+                available,
+                created: false,
+            });
+        }
+        Ok(Self { files })
+    }
+
+    // True when every file overlapping `offset..offset + len` is there and long
+    // enough to cover it.
+    pub fn is_available(&self, offset: u64, len: u64) -> bool {
+        let end = offset + len;
+        self.files
+            .iter()
+            .filter(|file| file.len > 0 && file.offset < end && file.offset + file.len > offset)
+            .all(|file| file.available >= end.min(file.offset + file.len) - file.offset)
+    }
+
+    // Calls `f` with each file overlapping `offset..offset + len`, the position
+    // within that file, and the matching range of the caller's buffer.
+    fn for_each_span<F>(&self, offset: u64, len: usize, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&mut File, u64, std::ops::Range<usize>) -> anyhow::Result<()>,
+    {
+        let end = offset + len as u64;
+        let mut covered = 0;
+        for file in &self.files {
+            let file_end = file.offset + file.len;
+            if file.len == 0 || file_end <= offset || file.offset >= end {
+                continue;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            let range = (start - offset) as usize..(stop - offset) as usize;
+            covered += range.len();
+            let mut handle = file.file.lock().unwrap();
+            let handle = handle.as_mut().context("file could not be opened")?;
+            f(handle, start - file.offset, range)?;
+        }
+        anyhow::ensure!(covered == len, "access past end of storage");
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.for_each_span(offset, data.len(), |file, pos, range| {
+            file.seek(SeekFrom::Start(pos))?;
+            file.write_all(&data[range])?;
+            Ok(())
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.for_each_span(offset, buf.len(), |file, pos, range| {
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut buf[range])?;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        for file in &self.files {
+            if let Some(handle) = &*file.file.lock().unwrap() {
+                handle.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_stamps(&self) -> anyhow::Result<Vec<FileStamp>> {
+        let mut stamps = Vec::new();
+        for file in &self.files {
+            let metadata = match &*file.file.lock().unwrap() {
+                Some(handle) => handle.metadata()?,
+                None => anyhow::bail!("file could not be opened"),
+            };
+            let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+            stamps.push(FileStamp {
+                length: metadata.len(),
+                mtime: mtime.as_nanos() as u64,
+            });
+        }
+        Ok(stamps)
+    }
+
+    fn is_fresh(&self, offset: u64, len: u64) -> bool {
+        let end = offset + len;
+        self.files
+            .iter()
+            .filter(|file| file.len > 0 && file.offset < end && file.offset + file.len > offset)
+            .all(|file| file.created)
+    }
+}
+
+pub struct MemoryStorage {
+    bytes: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: Mutex::new(vec![0u8; len]),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes.into_inner().unwrap()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let mut bytes = self.bytes.lock().unwrap();
+        let start = usize::try_from(offset)?;
+        let dest = bytes
+            .get_mut(start..start + data.len())
+            .ok_or_else(|| anyhow::anyhow!("write past end of storage"))?;
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let bytes = self.bytes.lock().unwrap();
+        let start = usize::try_from(offset)?;
+        let src = bytes
+            .get(start..start + buf.len())
+            .ok_or_else(|| anyhow::anyhow!("read past end of storage"))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::BencodeValue;
+
+    fn info() -> Info {
+        let dict = BencodeValue::Dict(vec![
+            (b"length".to_vec(), BencodeValue::Int(100)),
+            (b"name".to_vec(), BencodeValue::Bytes(b"data".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(16384)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(vec![0; 20])),
+        ]);
+        Info::from_bytes(&dict.encode()).unwrap()
+    }
+
+    #[test]
+    fn open_refuses_missing_files_without_creating_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        assert!(FileStorage::open(&path, &info()).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn open_refuses_wrongly_sized_files_without_resizing_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, [1; 50]).unwrap();
+        assert!(FileStorage::open(&path, &info()).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 50);
+
+        std::fs::write(&path, [1; 100]).unwrap();
+        let storage = FileStorage::open(&path, &info()).unwrap();
+        let mut buf = [0; 10];
+        storage.read(90, &mut buf).unwrap();
+        assert_eq!(buf, [1; 10]);
+        assert!(storage.write(0, &[2]).is_err());
+    }
+
+    #[test]
+    fn spans_a_piece_across_small_files_and_an_empty_one() {
+        let file = |length, name: &str| {
+            BencodeValue::Dict(vec![
+                (b"length".to_vec(), BencodeValue::Int(length)),
+                (
+                    b"path".to_vec(),
+                    BencodeValue::List(vec![BencodeValue::Bytes(name.as_bytes().to_vec())]),
+                ),
+            ])
+        };
+        let dict = BencodeValue::Dict(vec![
+            (
+                b"files".to_vec(),
+                BencodeValue::List(vec![file(3, "a"), file(0, "b"), file(2, "c"), file(5, "d")]),
+            ),
+            (b"name".to_vec(), BencodeValue::Bytes(b"multi".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(16)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(vec![0; 20])),
+        ]);
+        let info = Info::from_bytes(&dict.encode()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path(), &info).unwrap();
+
+        storage.write(1, b"123456").unwrap();
+        let mut buf = [0; 10];
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"\x00123456\x00\x00\x00");
+        let root = dir.path().join("multi");
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"\x0012");
+        assert_eq!(std::fs::read(root.join("b")).unwrap(), b"");
+        assert_eq!(std::fs::read(root.join("c")).unwrap(), b"34");
+        assert_eq!(std::fs::read(root.join("d")).unwrap(), b"56\x00\x00\x00");
+
+        let memory = MemoryStorage::new(10);
+        memory.write(1, b"123456").unwrap();
+        assert_eq!(memory.into_bytes(), buf);
+
+        assert!(storage.read(8, &mut [0; 3]).is_err());
+        assert!(storage.write(10, &[1]).is_err());
+    }
+}
@@ -1,34 +1,113 @@
 use anyhow::Context;
-use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
     path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
 };
-use tokio::{net::UdpSocket, task::JoinSet};
-use url::form_urlencoded;
 
 use crate::{
+    choker::{Choker, RECHOKE_INTERVAL},
+    decode::value_span,
+    dht::{Dht, DHT_INTERVAL},
+    extension::ExtensionRegistry,
     magnet::Magnet,
-    peer::Peer,
-    tracker::{TrackerRequest, TrackerResponse},
+    message::Message,
+    metadata::MetadataExtension,
+    peer::{Peer, BLOCK_SIZE},
+    pex::{PexExtension, PexPeer, PexState, PEX_INTERVAL, PEX_REACHABLE, PEX_SEED, UT_PEX},
+    picker::PiecePicker,
+    piece::PieceBuffer,
+    pipeline::{MAX_REQUESTS, MIN_REQUESTS},
+    resume::Resume,
+    seed::{read_block, UPLOAD_SLOTS},
+    storage::Storage,
+    tracker::{self, next_announce, AnnounceResponse, TrackerRequest, TrackerSession, Transfer},
 };
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone)]
 pub struct Torrent {
-    pub announce: String,
+    // Left out by trackerless torrents, whose peers come from the DHT.
+    pub announce: Option<String>,
     pub info: Info,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+// The keys of a .torrent file besides `info`, which is parsed from its own bytes.
+#[derive(Deserialize)]
+struct TorrentFile {
+    #[serde(default)]
+    announce: Option<String>,
+}
+
+pub struct DownloadOptions {
+    // Bounds on outstanding block requests per peer.
+    pub min_requests: usize,
+    pub max_requests: usize,
+    // Download pieces in order instead of rarest-first.
+    pub sequential: bool,
+    // Peers we upload to at once, not counting the optimistic unchoke.
+    pub upload_slots: usize,
+    // Also look for peers on the DHT, unless the torrent is private.
+    pub dht: Option<Arc<Dht>>,
+    // Takes incoming peers as well; its port is the one we announce.
+    pub listener: Option<Arc<TcpListener>>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            min_requests: MIN_REQUESTS,
+            max_requests: MAX_REQUESTS,
+            sequential: false,
+            upload_slots: UPLOAD_SLOTS,
+            dht: None,
+            listener: None,
+        }
+    }
+}
+
+pub struct DownloadSummary {
+    pub pieces: usize,
+    pub bytes: u64,
+    // Pieces that were already valid on disk and not requested again.
+    pub resumed: usize,
+    // Time from entering endgame mode to the end of the download.
+    pub endgame_time: Duration,
+    // Bytes of blocks that had already arrived from another peer.
+    pub duplicate_bytes: u64,
+}
+
+// Only made by `Info::from_bytes`, so there are always bytes to hash.
+#[derive(Clone)]
 pub struct Info {
-    #[serde(rename = "piece length")]
     pub piece_length: u32,
-    #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
     name: String,
+    // BEP 27: peers may only come from the tracker, so no PEX or DHT.
+    private: Option<i64>,
+    additional: Additional,
+    // The bencoded info dictionary exactly as received; this is what gets hashed.
+    raw: Vec<u8>,
+}
+
+// The keys of the info dictionary we use; the rest is kept in `Info::raw`.
+#[derive(Deserialize)]
+struct InfoFields {
+    #[serde(rename = "piece length")]
+    piece_length: u32,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    name: String,
+    #[serde(default)]
+    private: Option<i64>,
     #[serde(flatten)]
     additional: Additional,
 }
@@ -36,100 +115,158 @@ pub struct Info {
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 enum Additional {
-    SingleFile { length: u32 },
+    SingleFile { length: u64 },
     MultiFile { files: Vec<File> },
 }
 
 impl Info {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let fields = serde_bencode::from_bytes::<InfoFields>(bytes)?;
+        anyhow::ensure!(fields.piece_length > 0, "piece length is zero");
+        anyhow::ensure!(
+            fields.pieces.len() % 20 == 0,
+            "pieces is {} bytes, not a multiple of 20",
+            fields.pieces.len()
+        );
+        let info = Self {
+            piece_length: fields.piece_length,
+            pieces: fields.pieces,
+            name: fields.name,
+            private: fields.private,
+            additional: fields.additional,
+            raw: bytes.to_vec(),
+        };
+        let expected = info.file_len().div_ceil(info.piece_length as u64);
+        anyhow::ensure!(
+            info.num_pieces() as u64 == expected,
+            "{} piece hashes for {} bytes, expected {}",
+            info.num_pieces(),
+            info.file_len(),
+            expected
+        );
+        Ok(info)
+    }
+
+    pub fn hash(&self) -> [u8; 20] {
+        Sha1::digest(&self.raw).into()
+    }
+
+    // The bencoded dictionary, as handed out over ut_metadata.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
     pub fn pieces(&self) -> Vec<Vec<u8>> {
         self.pieces.chunks(20).map(|c| c.to_vec()).collect()
     }
 
-    pub fn file_len(&self) -> u32 {
+    pub fn file_len(&self) -> u64 {
         match &self.additional {
             Additional::SingleFile { length } => *length,
             Additional::MultiFile { files } => files.iter().map(|f| f.length).sum(),
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        matches!(self.additional, Additional::MultiFile { .. })
+    }
+
+    // A single-file torrent is treated as one file named after the torrent.
+    pub fn files(&self) -> Vec<File> {
+        match &self.additional {
+            Additional::SingleFile { length } => vec![File {
+                length: *length,
+                path: vec![self.name.clone()],
+            }],
+            Additional::MultiFile { files } => files.clone(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    pub fn piece_offset(&self, index: usize) -> u64 {
+        index as u64 * self.piece_length as u64
+    }
+
+    // Every piece is `piece_length` long except the last, which holds the remainder.
+    pub fn piece_len(&self, index: usize) -> u32 {
+        let remaining = self.file_len().saturating_sub(self.piece_offset(index));
+        remaining.min(self.piece_length as u64) as u32
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-struct File {
-    length: u32,
-    path: Vec<String>,
+pub struct File {
+    pub length: u64,
+    pub path: Vec<String>,
 }
 
 impl Torrent {
     pub fn new(file_name: PathBuf) -> anyhow::Result<Self> {
         let content = std::fs::read(file_name)?;
-        Ok(serde_bencode::from_bytes::<Self>(&content)?)
+        let file = serde_bencode::from_bytes::<TorrentFile>(&content)?;
+        let info_span = value_span(&content, &[b"info"])?;
+        Ok(Self {
+            announce: file.announce,
+            info: Info::from_bytes(&content[info_span])?,
+        })
     }
 
-    pub fn from_magnet_and_metadata(magnet: Magnet, metadata: Info) -> anyhow::Result<Self> {
+    pub fn from_magnet_and_metadata(magnet: &Magnet, metadata: Info) -> anyhow::Result<Self> {
         Ok(Self {
-            announce: magnet.tracker_url.unwrap().to_string(),
+            announce: magnet.tracker_url.as_ref().map(|url| url.to_string()),
             info: metadata,
         })
     }
 
-    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        Ok(Sha1::digest(serde_bencode::to_bytes(&self.info)?).into())
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info.hash()
     }
 
-    pub fn len(&self) -> u32 {
+    pub fn len(&self) -> u64 {
         self.info.file_len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn pieces(&self) -> Vec<Vec<u8>> {
         self.info.pieces()
     }
 
     pub async fn get_peer_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash()?).collect();
-        let request = TrackerRequest::new(self.len());
-        let announce = &self.announce;
-        if announce.starts_with("http") {
-            let params = serde_urlencoded::to_string(&request)?;
-            let url = format!("{}?{}&info_hash={}", announce, params, info_hash_str);
-            let response = reqwest::get(url).await?;
-            let tracker_response =
-                serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
-            let peer_addrs = tracker_response.peers();
-            println!("Found peers: {:?}", peer_addrs);
-            Ok(peer_addrs)
-        } else if announce.starts_with("udp") {
-            let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-            let address = Self::parse_udp_url(announce)?;
-            sock.connect(address).await?;
-            Ok(vec![])
-        } else {
-            Err(anyhow::anyhow!("Unsupported tracker protocol"))
-        }
+        let response = self.announce(&TrackerRequest::new(self.len())).await?;
+        println!("Found peers: {:?}", response.peers);
+        Ok(response.peers)
     }
 
-    fn parse_udp_url(url: &str) -> anyhow::Result<String> {
-        let parts: Vec<&str> = url.split(':').collect();
-        let host = parts[1].trim_start_matches('/');
-        let port = parts[2];
-        let addr = format!("{}:{}", host, port);
-        Ok(addr)
+    pub async fn announce(&self, request: &TrackerRequest) -> anyhow::Result<AnnounceResponse> {
+        let url = self.announce.as_deref().context("torrent has no tracker")?;
+        tracker::announce(url, self.info_hash(), request).await
     }
 
     pub async fn download_piece(&self, piece: usize) -> anyhow::Result<Vec<u8>> {
         let peer_addrs = self.get_peer_addrs().await?;
-        let info_hash = self.info_hash()?;
+        let info_hash = self.info_hash();
         for peer_address in peer_addrs {
             match Peer::new(peer_address, info_hash).await {
                 Ok(mut peer) => {
-                    let pieces = peer.get_pieces().await?;
+                    let (pieces, _) = peer.initial_pieces().await?;
                     if pieces.contains(&piece) {
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            self.info.piece_length,                      // piece_len
-                            self.len() - piece * self.info.piece_length, // last piece
-                        );
+                        let piece_len = self.info.piece_len(piece);
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
@@ -139,93 +276,581 @@ impl Torrent {
         Err(anyhow::anyhow!("Could not find peer"))
     }
 
-    pub async fn download(&self) -> anyhow::Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
+    pub async fn download(
+        &self,
+        storage: Arc<dyn Storage>,
+        resume: &mut Resume,
+        options: &DownloadOptions,
+    ) -> anyhow::Result<DownloadSummary> {
         let piece_hashes = self.pieces();
         let num_pieces = piece_hashes.len();
-        let info_hash = self.info_hash()?;
-        let piece_len = self.info.piece_length;
-        let file_len = self.len();
-
-        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
-        let mut join_set = JoinSet::new();
+        let resumed = resume.count();
+        let mut summary = DownloadSummary {
+            pieces: 0,
+            bytes: 0,
+            resumed,
+            endgame_time: Duration::ZERO,
+            duplicate_bytes: 0,
+        };
+        if resume.is_complete() {
+            return Ok(summary);
+        }
+        if resumed > 0 {
+            println!("Resuming with {}/{} pieces on disk", resumed, num_pieces);
+        }
 
-        for peer_address in peer_addrs {
-            match Peer::new(peer_address, info_hash).await {
-                Ok(mut peer) => {
-                    let pieces = peer.get_pieces().await?;
-                    for piece in pieces {
-                        peer_piece_map
-                            .entry(piece)
-                            .or_insert_with(Vec::new)
-                            .push(peer.clone());
-                    }
-                    peer.prepare_download().await?;
-                }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+        let left = (0..num_pieces)
+            .filter(|piece| !resume.has(*piece))
+            .map(|piece| self.info.piece_len(piece) as u64)
+            .sum();
+        let mut transfer = Transfer {
+            left,
+            ..Default::default()
+        };
+        let mut request = TrackerRequest::new(left);
+        if let Some(listener) = &options.listener {
+            request = request.with_port(listener.local_addr()?.port());
+        }
+        let mut tracker = self
+            .announce
+            .clone()
+            .map(|url| TrackerSession::new(url, self.info_hash(), request));
+        let result = self
+            .download_from(
+                tracker.as_mut(),
+                &mut transfer,
+                storage,
+                resume,
+                options,
+                &mut summary,
+            )
+            .await;
+        let Some(tracker) = &mut tracker else {
+            return result.map(|()| summary);
+        };
+        if result.is_ok() {
+            tracker.complete();
+            if let Err(e) = tracker.announce(transfer).await {
+                eprintln!("Announce to {} failed: {}", tracker.url(), e);
             }
         }
+        // Whether we finished or not, the tracker should stop handing out our address.
+        if let Err(e) = tracker.stopped(transfer).await {
+            eprintln!("Announce to {} failed: {}", tracker.url(), e);
+        }
+        result.map(|()| summary)
+    }
 
-        if peer_piece_map.is_empty() {
-            return Err(anyhow::anyhow!("Could not connect to any peers"));
+    // The download proper, keeping `transfer` current for the announces.
+    async fn download_from(
+        &self,
+        mut tracker: Option<&mut TrackerSession>,
+        transfer: &mut Transfer,
+        storage: Arc<dyn Storage>,
+        resume: &mut Resume,
+        options: &DownloadOptions,
+        summary: &mut DownloadSummary,
+    ) -> anyhow::Result<()> {
+        let piece_hashes = self.pieces();
+        let num_pieces = piece_hashes.len();
+        let mut picker = PiecePicker::new(num_pieces);
+        picker.set_sequential(options.sequential);
+        for piece in (0..num_pieces).filter(|piece| resume.has(*piece)) {
+            picker.mark_have(piece);
         }
 
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
+        let info_hash = self.info_hash();
+        // BEP 27 keeps private torrents off the DHT.
+        let dht = options.dht.clone().filter(|_| !self.info.is_private());
+        let mut peer_addrs = Vec::new();
+        if let Some(tracker) = tracker.as_deref_mut() {
+            match tracker.announce(*transfer).await {
+                Ok(response) => peer_addrs = response.peer_groups,
+                // The DHT may still find peers.
+                Err(e) if dht.is_some() => eprintln!("Announce to {} failed: {}", tracker.url(), e),
+                Err(e) => return Err(e),
+            }
+            println!("Found peers: {:?}", peer_addrs.concat());
+        }
+        // Lookups run in the background, the first one right away.
+        let mut lookups = JoinSet::new();
+        let find_peers = |lookups: &mut JoinSet<_>| {
+            if let Some(dht) = dht.clone() {
+                lookups.spawn(async move { dht.find_peers(info_hash).await });
+            }
         };
+        find_peers(&mut lookups);
+        let mut dht_tick =
+            tokio::time::interval_at(tokio::time::Instant::now() + DHT_INTERVAL, DHT_INTERVAL);
 
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
-            let piece_hashes = piece_hashes.clone();
-            let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
-
-            join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
-                    Ok(data) => {
-                        println!(
-                            "Downloaded piece {}/{} from peer {}",
-                            piece_number, num_pieces, peer.address
-                        );
-                        if piece_hashes[piece] != *Sha1::digest(&data) {
-                            eprintln!(
-                                "Piece {}/{} failed verification. Will retry...",
-                                piece_number, num_pieces
-                            );
-                            (piece, vec![])
-                        } else {
-                            (piece, data)
+        // Peers are indexed the same here and in the picker.
+        let mut peers: Vec<Option<Peer>> = Vec::new();
+        let (messages_tx, mut messages) = mpsc::unbounded_channel();
+        let mut forwarders = JoinSet::new();
+        // Every address we tried, so re-announces only bring in new peers.
+        let mut tried = HashSet::new();
+        let mut connecting = JoinSet::new();
+        let (pex_tx, mut pex_peers) = mpsc::unbounded_channel();
+        let mut extensions = ExtensionRegistry::new()
+            .with_handler(MetadataExtension::new(Some(self.info.raw().to_vec())));
+        if !self.info.is_private() {
+            extensions = extensions.with_handler(PexExtension::new(Some(pex_tx)));
+        }
+        if let Some(listener) = &options.listener {
+            extensions = extensions.with_listen_port(listener.local_addr()?.port());
+        }
+        let extensions = Arc::new(extensions);
+        // What each peer has heard from us over PEX, by index.
+        let mut pex_states: HashMap<usize, PexState> = HashMap::new();
+        let mut pex_tick = tokio::time::interval(PEX_INTERVAL);
+        // Takes the addresses of each peer together, so one connection races
+        // them all and the peer is only connected to once.
+        let connect = |connecting: &mut JoinSet<_>,
+                       tried: &mut HashSet<SocketAddr>,
+                       peer_addrs: Vec<Vec<SocketAddr>>,
+                       picker: &PiecePicker| {
+            // BEP 3 allows leaving out an empty BITFIELD.
+            let bitfield = (picker.have_count() > 0).then(|| picker.bitfield());
+            for mut addresses in peer_addrs {
+                addresses.retain(|address| tried.insert(*address));
+                let Some(&address) = addresses.first() else {
+                    continue;
+                };
+                let bitfield = bitfield.clone();
+                let extensions = extensions.clone();
+                let (min, max) = (options.min_requests, options.max_requests);
+                connecting.spawn(async move {
+                    (
+                        address,
+                        connect_peer(&addresses, info_hash, bitfield, min, max, extensions).await,
+                    )
+                });
+            }
+        };
+        connect(&mut connecting, &mut tried, peer_addrs, &picker);
+
+        let spawn =
+            |join_set: &mut JoinSet<_>, mut peer: Peer, index: usize, piece: Arc<PieceBuffer>| {
+                join_set.spawn(async move {
+                    let result = peer.fetch_blocks(&piece).await;
+                    (index, peer.address, piece.index() as usize, result)
+                });
+            };
+
+        let blocks_per_piece = self.info.piece_length.div_ceil(BLOCK_SIZE) as usize;
+        let mut assigned = Vec::new();
+        // Pieces being downloaded, with the peers working on each. A buffer
+        // outlives failed attempts so a retry only fetches the missing blocks.
+        let mut in_progress: HashMap<usize, (Arc<PieceBuffer>, Vec<usize>)> = HashMap::new();
+        let mut endgame_since = None;
+        let mut join_set = JoinSet::new();
+        let mut choker = Choker::new(options.upload_slots);
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+
+        while !picker.is_complete() {
+            // Give every unchoked peer enough pieces to keep its request window full.
+            for (index, peer) in peers.iter().enumerate() {
+                let Some(peer) = peer else { continue };
+                if peer.choke_state().peer_choking {
+                    continue;
+                }
+                let wanted = peer.request_limit().div_ceil(blocks_per_piece) + 1;
+                while assigned[index] < wanted {
+                    let piece = match picker.pick(index, &mut rand::thread_rng()) {
+                        Some(piece) => piece,
+                        // Nothing left to pick: help with pieces other peers are on.
+                        None if picker.is_endgame() => {
+                            if endgame_since.is_none() {
+                                println!("Entering endgame with {} pieces left", in_progress.len());
+                                endgame_since = Some(Instant::now());
+                            }
+                            let helping = in_progress.iter().find(|(piece, (_, workers))| {
+                                picker.has_piece(index, **piece) && !workers.contains(&index)
+                            });
+                            match helping {
+                                Some((piece, _)) => *piece,
+                                None => break,
+                            }
                         }
+                        None => break,
+                    };
+                    let (buffer, workers) = in_progress.entry(piece).or_insert_with(|| {
+                        let buffer = PieceBuffer::new(piece as u32, self.info.piece_len(piece));
+                        (Arc::new(buffer), Vec::new())
+                    });
+                    workers.push(index);
+                    assigned[index] += 1;
+                    spawn(&mut join_set, peer.clone(), index, buffer.clone());
+                }
+            }
+
+            // The timers never run out, so stop here once there is nobody
+            // left to download from.
+            if join_set.is_empty()
+                && connecting.is_empty()
+                && lookups.is_empty()
+                && peers.iter().all(Option::is_none)
+            {
+                break;
+            }
+            tokio::select! {
+                Some(join_result) = join_set.join_next() => {
+                    let (index, address, piece, result) = join_result.context("Task panicked")?;
+                    assigned[index] -= 1;
+                    match result {
+                        Ok(duplicate_bytes) => summary.duplicate_bytes += duplicate_bytes,
+                        Err(e) => eprintln!(
+                            "Error loading piece {}/{}: {}. Will retry...",
+                            piece + 1,
+                            num_pieces,
+                            e
+                        ),
                     }
-                    Err(e) => {
+                    // Gone if another peer already finished the piece.
+                    let Some((buffer, workers)) = in_progress.get_mut(&piece) else {
+                        continue;
+                    };
+                    workers.retain(|worker| *worker != index);
+                    let buffer = buffer.clone();
+                    let idle = workers.is_empty();
+
+                    if buffer.is_complete() {
+                        let data = buffer.data();
+                        if piece_hashes[piece] == *Sha1::digest(&data) {
+                            println!(
+                                "Downloaded piece {}/{} from peer {}",
+                                piece + 1,
+                                num_pieces,
+                                address
+                            );
+                            // Anyone still on the piece sees it complete and cancels.
+                            in_progress.remove(&piece);
+                            storage.write(self.info.piece_offset(piece), &data)?;
+                            resume.mark_done(piece, storage.as_ref())?;
+                            picker.mark_have(piece);
+                            summary.pieces += 1;
+                            summary.bytes += data.len() as u64;
+                            transfer.downloaded += data.len() as u64;
+                            transfer.left -= data.len() as u64;
+                            for (index, peer) in peers.iter_mut().enumerate() {
+                                if let Some(peer) = peer {
+                                    // Fails only for a peer that is gone, which we hear about next.
+                                    let _ = peer.send(Message::Have(piece as u32)).await;
+                                    let _ = peer.set_interested(picker.is_interesting(index)).await;
+                                }
+                            }
+                            continue;
+                        }
                         eprintln!(
-                            "Error loading piece {}/{}: {}. Will retry...",
-                            piece_number, num_pieces, e
+                            "Piece {}/{} failed verification. Will retry...",
+                            piece + 1,
+                            num_pieces
                         );
-                        (piece, vec![])
+                        buffer.reset();
+                    }
+                    if idle {
+                        picker.abort(piece);
                     }
                 }
-            });
-        };
+                Some((index, msg)) = messages.recv() => match msg {
+                    Some(Message::Have(piece)) => {
+                        picker.peer_has(index, piece as usize);
+                        if let Some(peer) = &mut peers[index] {
+                            // A gone peer is reported by its forwarder; don't fail the download.
+                            let _ = peer.set_interested(picker.is_interesting(index)).await;
+                        }
+                    }
+                    Some(Message::Bitfield(bitfield)) => picker.peer_bitfield(index, &bitfield),
+                    Some(Message::Request(block)) => {
+                        let Some(peer) = &mut peers[index] else { continue };
+                        // Requests from peers we choke are dropped, as BEP 3 says.
+                        if peer.choke_state().am_choking || !picker.have(block.index as usize) {
+                            continue;
+                        }
+                        match read_block(&self.info, storage.as_ref(), block) {
+                            Ok(data) => {
+                                let len = data.len() as u64;
+                                if peer.send_block(block.index, block.begin, data).await.is_ok() {
+                                    transfer.uploaded += len;
+                                }
+                            }
+                            Err(e) => eprintln!("{} -> {}", peer.address, e),
+                        }
+                    }
+                    // Choke and interest state is tracked by the connection.
+                    Some(_) => {}
+                    None => {
+                        picker.remove_peer(index);
+                        peers[index] = None;
+                        pex_states.remove(&index);
+                    }
+                },
+                Some(added) = pex_peers.recv() => {
+                    let addresses = added.into_iter().map(|peer| vec![peer.address]).collect();
+                    connect(&mut connecting, &mut tried, addresses, &picker);
+                }
+                _ = pex_tick.tick(), if !self.info.is_private() => {
+                    // Peers we dialed are reachable by others as well. Those that
+                    // dialed us are only of use at the listen port they told us.
+                    let connected: Vec<_> = peers
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, peer)| {
+                            let peer = peer.as_ref()?;
+                            let seed = (0..num_pieces).all(|piece| picker.has_piece(index, piece));
+                            let seed = if seed { PEX_SEED } else { 0 };
+                            if tried.contains(&peer.address) {
+                                return Some(PexPeer { address: peer.address, flags: PEX_REACHABLE | seed });
+                            }
+                            let address = SocketAddr::new(peer.address.ip(), peer.listen_port()?);
+                            Some(PexPeer { address, flags: seed })
+                        })
+                        .collect();
+                    for (index, peer) in peers.iter_mut().enumerate() {
+                        let Some(peer) = peer else { continue };
+                        if peer.extensions().and_then(|h| h.id(UT_PEX)).is_none() {
+                            continue;
+                        }
+                        let state = pex_states.entry(index).or_default();
+                        if let Some(payload) = state.update(&connected, peer.address) {
+                            let _ = peer.send_extension(UT_PEX, payload).await;
+                        }
+                    }
+                }
+                Some(connected) = connecting.join_next() => {
+                    let (address, result) = connected.context("Task panicked")?;
+                    let (mut peer, pieces, first) = match result {
+                        Ok(connected) => connected,
+                        Err(e) => {
+                            eprintln!("{} -> {}", address, e);
+                            continue;
+                        }
+                    };
+                    let index = picker.add_peer();
+                    for piece in pieces {
+                        picker.peer_has(index, piece);
+                    }
+                    // If this fails the forwarder reports the peer gone.
+                    let _ = peer.set_interested(picker.is_interesting(index)).await;
+                    // Queued ahead of everything the forwarder passes on after it.
+                    if let Some(msg) = first {
+                        let _ = messages_tx.send((index, Some(msg)));
+                    }
+                    forwarders.spawn(forward_messages(peer.clone(), index, messages_tx.clone()));
+                    peers.push(Some(peer));
+                    assigned.push(0);
+                }
+                accepted = accept(options.listener.as_deref()) => {
+                    let (stream, address) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            eprintln!("Accepting a peer failed: {}", e);
+                            continue;
+                        }
+                    };
+                    // IPv4 peers on a dual-stack listener show up as ::ffff:a.b.c.d.
+                    let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+                    let bitfield = (picker.have_count() > 0).then(|| picker.bitfield());
+                    let extensions = extensions.clone();
+                    let (min, max) = (options.min_requests, options.max_requests);
+                    connecting.spawn(async move {
+                        let result = async {
+                            let (peer, _) = Peer::accept(stream, address, |hash| *hash == info_hash).await?;
+                            start_peer(peer, bitfield, min, max, extensions).await
+                        };
+                        (address, result.await)
+                    });
+                }
+                Some(found) = lookups.join_next() => {
+                    let found = found.context("Task panicked")?;
+                    println!("Found peers on the DHT: {:?}", found);
+                    let found = found.into_iter().map(|peer| vec![peer]).collect();
+                    connect(&mut connecting, &mut tried, found, &picker);
+                }
+                _ = dht_tick.tick(), if dht.is_some() => find_peers(&mut lookups),
+                _ = next_announce(tracker.as_deref()) => {
+                    let Some(tracker) = tracker.as_deref_mut() else { continue };
+                    match tracker.announce(*transfer).await {
+                        Ok(response) => connect(&mut connecting, &mut tried, response.peer_groups, &picker),
+                        Err(e) => eprintln!("Announce to {} failed: {}", tracker.url(), e),
+                    }
+                }
+                _ = rechoke.tick() => {
+                    // Reciprocate to the peers we download from fastest.
+                    let stats: Vec<_> = peers
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, peer)| Some(peer.as_ref()?.choker_stats(index)))
+                        .collect();
+                    let unchoked = choker.rechoke(&stats, false, &mut rand::thread_rng());
+                    for (index, peer) in peers.iter_mut().enumerate() {
+                        if let Some(peer) = peer {
+                            let _ = peer.set_choking(!unchoked.contains(&index)).await;
+                        }
+                    }
+                }
+            }
+        }
+        storage.flush()?;
+        if let Some(since) = endgame_since {
+            summary.endgame_time = since.elapsed();
+        }
+        anyhow::ensure!(!peers.is_empty(), "Could not connect to any peers");
+        anyhow::ensure!(
+            picker.is_complete(),
+            "all peers disconnected with {} of {} pieces missing",
+            num_pieces - picker.have_count(),
+            num_pieces
+        );
+        Ok(())
+    }
+}
 
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
+// Connects to a peer at whichever of its addresses answers first and trades
+// BITFIELDs, returning the pieces it has and the first message if it wasn't a
+// BITFIELD.
+async fn connect_peer(
+    addresses: &[SocketAddr],
+    info_hash: [u8; 20],
+    bitfield: Option<Vec<u8>>,
+    min_requests: usize,
+    max_requests: usize,
+    extensions: Arc<ExtensionRegistry>,
+) -> anyhow::Result<(Peer, Vec<usize>, Option<Message>)> {
+    let peer = Peer::connect(addresses, info_hash).await?;
+    start_peer(peer, bitfield, min_requests, max_requests, extensions).await
+}
+
+// Trades BITFIELDs and extension handshakes on a fresh connection, whichever
+// side opened it, returning what `connect_peer` does.
+async fn start_peer(
+    mut peer: Peer,
+    bitfield: Option<Vec<u8>>,
+    min_requests: usize,
+    max_requests: usize,
+    extensions: Arc<ExtensionRegistry>,
+) -> anyhow::Result<(Peer, Vec<usize>, Option<Message>)> {
+    peer.set_request_limits(min_requests, max_requests);
+    if let Some(bitfield) = bitfield {
+        peer.send(Message::Bitfield(bitfield)).await?;
+    }
+    let (pieces, first) = peer.initial_pieces().await?;
+    // Lets the peer tell us its `reqq` and fetch the metadata from us.
+    if peer.supports_extension {
+        peer.set_extensions(extensions);
+        peer.send_extension_handshake().await?;
+    }
+    Ok((peer, pieces, first))
+}
+
+// The next peer connecting to `listener`, or never without one.
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Passes a peer's messages on to the download loop, then `None` once it has
+// disconnected.
+async fn forward_messages(
+    mut peer: Peer,
+    index: usize,
+    messages: mpsc::UnboundedSender<(usize, Option<Message>)>,
+) {
+    while let Ok(msg) = peer.recv().await {
+        if messages.send((index, Some(msg))).is_err() {
+            return;
         }
+    }
+    let _ = messages.send((index, None));
+}
 
-        let mut file_bytes = vec![0u8; file_len as usize];
-        while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
-            } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::BencodeValue;
+
+    const TIB: u64 = 1 << 40;
+    const PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
+    // An info dictionary for files of these lengths, with zeroed piece hashes.
+    fn info(lengths: &[u64]) -> Info {
+        let total: u64 = lengths.iter().sum();
+        let num_pieces = total.div_ceil(PIECE_LENGTH as u64) as usize;
+        let bytes = |s: &str| BencodeValue::Bytes(s.as_bytes().to_vec());
+        let mut dict = Vec::new();
+        if let [length] = lengths {
+            dict.push((b"length".to_vec(), BencodeValue::Int(*length as i64)));
+        } else {
+            let files = lengths.iter().enumerate().map(|(i, length)| {
+                BencodeValue::Dict(vec![
+                    (b"length".to_vec(), BencodeValue::Int(*length as i64)),
+                    (
+                        b"path".to_vec(),
+                        BencodeValue::List(vec![bytes(&i.to_string())]),
+                    ),
+                ])
+            });
+            dict.push((b"files".to_vec(), BencodeValue::List(files.collect())));
         }
+        dict.extend([
+            (b"name".to_vec(), bytes("huge")),
+            (
+                b"piece length".to_vec(),
+                BencodeValue::Int(PIECE_LENGTH as i64),
+            ),
+            (
+                b"pieces".to_vec(),
+                BencodeValue::Bytes(vec![0; num_pieces * 20]),
+            ),
+        ]);
+        Info::from_bytes(&BencodeValue::Dict(dict).encode()).unwrap()
+    }
+
+    #[test]
+    fn counts_pieces_of_a_multi_terabyte_file() {
+        let info = info(&[5 * TIB + 1]);
+        assert_eq!(info.file_len(), 5 * TIB + 1);
+        assert_eq!(info.num_pieces(), 327_681);
+        assert_eq!(info.piece_len(0), PIECE_LENGTH);
+        assert_eq!(info.piece_len(327_679), PIECE_LENGTH);
+        assert_eq!(info.piece_len(327_680), 1);
+    }
+
+    #[test]
+    fn offsets_pass_four_gib() {
+        let info = info(&[5 * TIB + 1]);
+        assert_eq!(info.piece_offset(255), (1 << 32) - PIECE_LENGTH as u64);
+        assert_eq!(info.piece_offset(256), 1 << 32);
+        assert_eq!(info.piece_offset(327_680), 5 * TIB);
+    }
 
-        Ok(file_bytes)
+    #[test]
+    fn sums_multi_file_lengths_past_four_gib() {
+        let info = info(&[3 * TIB, 2 * TIB + 12345, 5]);
+        assert_eq!(info.file_len(), 5 * TIB + 12350);
+        assert_eq!(info.num_pieces(), 327_681);
+        assert_eq!(info.piece_len(327_680), 12350);
+        let lengths: Vec<_> = info.files().iter().map(|f| f.length).collect();
+        assert_eq!(lengths, [3 * TIB, 2 * TIB + 12345, 5]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_inconsistent_piece_fields() {
+        let dict = |piece_length, pieces| {
+            BencodeValue::Dict(vec![
+                (b"length".to_vec(), BencodeValue::Int(100)),
+                (b"name".to_vec(), BencodeValue::Bytes(b"x".to_vec())),
+                (b"piece length".to_vec(), BencodeValue::Int(piece_length)),
+                (b"pieces".to_vec(), BencodeValue::Bytes(vec![0; pieces])),
+            ])
+            .encode()
+        };
+        assert!(Info::from_bytes(&dict(40, 60)).is_ok());
+        assert!(Info::from_bytes(&dict(0, 0)).is_err());
+        assert!(Info::from_bytes(&dict(40, 59)).is_err());
+        assert!(Info::from_bytes(&dict(40, 40)).is_err());
+        assert!(Info::from_bytes(&dict(40, 80)).is_err());
+    }
+}
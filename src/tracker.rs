@@ -1,20 +1,62 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+use tokio::{net::lookup_host, time::Instant};
+use url::{form_urlencoded, Url};
 
-use crate::peer::Peer;
+use crate::{peer::Peer, udp_tracker::UdpTracker};
 
-#[derive(Debug, Serialize)]
+// Used until the tracker tells us its interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// How soon a failed announce is tried again, unless `min interval` says otherwise.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// Long enough for a couple of UDP retransmissions, short enough not to hold up
+// the download for long when the tracker is down.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+// Anything routed the way the public internet is; nothing is sent there.
+const PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 6881);
+const PROBE_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+    6881,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
-    peer_id: String,
-    port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: u32,
-    compact: u8,
+    pub(crate) peer_id: String,
+    pub(crate) port: u16,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) compact: u8,
+    pub(crate) key: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) event: Option<AnnounceEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) trackerid: Option<String>,
+    // BEP 7: our address in the family the tracker may not see us announce
+    // from, so it can hand it to peers of either kind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ipv4: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ipv6: Option<Ipv6Addr>,
 }
 
 impl TrackerRequest {
-    pub fn new(left: u32) -> Self {
+    pub fn new(left: u64) -> Self {
         let peer_id = Peer::gen_peer_id();
         Self {
             peer_id,
@@ -23,26 +65,640 @@ impl TrackerRequest {
             downloaded: 0,
             left,
             compact: 1,
+            key: rand::random(),
+            event: None,
+            trackerid: None,
+            ipv4: match local_address(PROBE_V4) {
+                Some(IpAddr::V4(ip)) => Some(ip),
+                _ => None,
+            },
+            ipv6: match local_address(PROBE_V6) {
+                Some(IpAddr::V6(ip)) => Some(ip),
+                _ => None,
+            },
+        }
+    }
+
+    // The port our listener accepts peers on.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    // The request for a tracker at `addresses`. Whichever family we announce
+    // over, the tracker sees that address itself, so only the others are sent.
+    // A tracker reachable over both may get either, so it is sent neither.
+    fn for_tracker(&self, addresses: &[SocketAddr]) -> Self {
+        let mut request = self.clone();
+        if addresses.iter().any(SocketAddr::is_ipv4) {
+            request.ipv4 = None;
+        }
+        if addresses.iter().any(SocketAddr::is_ipv6) {
+            request.ipv6 = None;
         }
+        request
     }
 }
 
+// Every key is optional: a failed announce carries only `failure reason`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TrackerResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
     interval: Option<u32>,
-    #[serde(with = "serde_bytes")]
-    peers: Vec<u8>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    // Seeders and leechers.
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    #[serde(default)]
+    peers: Peers,
+    // BEP 7: compact IPv6 peers, 18 bytes each.
+    peers6: Option<ByteBuf>,
+}
+
+// Trackers send the compact model when we ask for it, but some still use the
+// original list of dictionaries.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dictionary(Vec<PeerEntry>),
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Self::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerEntry {
+    #[serde(rename = "peer id")]
+    peer_id: Option<ByteBuf>,
+    // An IP address or, rarely, a DNS name.
+    ip: String,
+    port: u16,
 }
 
 impl TrackerResponse {
+    // Parses a response, turning `failure reason` into a `TrackerFailure`.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let response = serde_bencode::from_bytes::<Self>(bytes)?;
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerFailure { reason }.into());
+        }
+        Ok(response)
+    }
+
     pub fn peers(&self) -> Vec<SocketAddr> {
-        self.peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
-                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                SocketAddr::new(ip, port)
-            })
-            .collect()
-    }
-}
\ No newline at end of file
+        self.peer_groups().concat()
+    }
+
+    // The peers' addresses, those of one peer together. Only dictionary
+    // entries sharing a peer id are known to be the same peer, e.g. one
+    // listed by both its IPv4 and its IPv6 address.
+    pub fn peer_groups(&self) -> Vec<Vec<SocketAddr>> {
+        let mut groups: Vec<Vec<SocketAddr>> = Vec::new();
+        match &self.peers {
+            Peers::Compact(bytes) => groups.extend(
+                parse_compact_peers(bytes)
+                    .into_iter()
+                    .map(|peer| vec![peer]),
+            ),
+            Peers::Dictionary(entries) => {
+                let mut by_id: HashMap<&ByteBuf, usize> = HashMap::new();
+                for entry in entries {
+                    // Peers given by DNS name are skipped rather than resolved.
+                    let Ok(ip) = entry.ip.parse() else { continue };
+                    let address = SocketAddr::new(ip, entry.port);
+                    match entry.peer_id.as_ref().and_then(|id| by_id.get(id)) {
+                        Some(&group) => groups[group].push(address),
+                        None => {
+                            if let Some(id) = &entry.peer_id {
+                                by_id.insert(id, groups.len());
+                            }
+                            groups.push(vec![address]);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(bytes) = &self.peers6 {
+            groups.extend(
+                parse_compact_peers6(bytes)
+                    .into_iter()
+                    .map(|peer| vec![peer]),
+            );
+        }
+        groups
+    }
+}
+
+// The tracker refused our announce.
+#[derive(Debug)]
+pub struct TrackerFailure {
+    pub reason: String,
+}
+
+impl fmt::Display for TrackerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tracker failure: {}", self.reason)
+    }
+}
+
+impl std::error::Error for TrackerFailure {}
+
+// What an announce over either protocol tells us.
+#[derive(Debug)]
+pub struct AnnounceResponse {
+    pub interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub tracker_id: Option<String>,
+    // The announce went through, but the tracker has something to say.
+    pub warning: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+    // `peers` again, with the addresses known to be one peer's together.
+    pub peer_groups: Vec<Vec<SocketAddr>>,
+}
+
+impl From<TrackerResponse> for AnnounceResponse {
+    fn from(response: TrackerResponse) -> Self {
+        let seconds = |secs: u32| Duration::from_secs(secs.into());
+        Self {
+            interval: response.interval.map(seconds),
+            min_interval: response.min_interval.map(seconds),
+            peers: response.peers(),
+            peer_groups: response.peer_groups(),
+            tracker_id: response.tracker_id,
+            warning: response.warning_message,
+            seeders: response.complete,
+            leechers: response.incomplete,
+        }
+    }
+}
+
+// Announces to an http(s) or udp tracker.
+pub async fn announce(
+    url: &str,
+    info_hash: [u8; 20],
+    request: &TrackerRequest,
+) -> anyhow::Result<AnnounceResponse> {
+    if url.starts_with("http") {
+        let parsed = Url::parse(url)?;
+        let host = parsed.host_str().context("tracker url has no host")?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addresses: Vec<_> = lookup_host((host, port)).await?.collect();
+        let info_hash_str: String = form_urlencoded::byte_serialize(&info_hash).collect();
+        let params = serde_urlencoded::to_string(request.for_tracker(&addresses))?;
+        let url = format!("{}?{}&info_hash={}", url, params, info_hash_str);
+        let response = reqwest::get(url).await?;
+        Ok(TrackerResponse::from_bytes(&response.bytes().await?)?.into())
+    } else if url.starts_with("udp") {
+        let mut tracker = UdpTracker::new(url).await?;
+        let response = tracker.announce(info_hash, request).await?;
+        Ok(AnnounceResponse {
+            interval: Some(Duration::from_secs(response.interval.into())),
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            seeders: Some(response.seeders),
+            leechers: Some(response.leechers),
+            peer_groups: response.peers.iter().map(|peer| vec![*peer]).collect(),
+            peers: response.peers,
+        })
+    } else {
+        Err(anyhow::anyhow!("Unsupported tracker protocol"))
+    }
+}
+
+// Byte counters reported to the tracker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transfer {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+// Our announces to one tracker over the life of a torrent: `started` first,
+// then regular announces at the tracker's interval, `completed` once the last
+// piece verified and `stopped` when we leave. Events that fail to get through
+// are sent again with the next announce.
+pub struct TrackerSession {
+    url: String,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    started: bool,
+    completed: bool,
+    interval: Duration,
+    min_interval: Duration,
+    next: Instant,
+}
+
+impl TrackerSession {
+    // `request` carries what stays the same between announces: peer id, port and key.
+    pub fn new(url: String, info_hash: [u8; 20], request: TrackerRequest) -> Self {
+        Self {
+            url,
+            info_hash,
+            request,
+            started: false,
+            completed: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: Duration::ZERO,
+            next: Instant::now(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // When the next regular announce is due.
+    pub fn next_announce(&self) -> Instant {
+        self.next
+    }
+
+    // Queues `completed` for the next announce and makes it due now.
+    pub fn complete(&mut self) {
+        self.completed = true;
+        self.next = Instant::now();
+    }
+
+    // Sends whichever events are pending, in order: `started` if it never got
+    // through, then `completed`.
+    pub async fn announce(&mut self, transfer: Transfer) -> anyhow::Result<AnnounceResponse> {
+        if !self.started {
+            let response = self.send(Some(AnnounceEvent::Started), transfer).await?;
+            if !self.completed {
+                return Ok(response);
+            }
+        }
+        let event = self.completed.then_some(AnnounceEvent::Completed);
+        self.send(event, transfer).await
+    }
+
+    // Tells the tracker we are gone, if it ever heard from us, and that we
+    // completed first if that never got through.
+    pub async fn stopped(&mut self, transfer: Transfer) -> anyhow::Result<()> {
+        if self.started {
+            if self.completed {
+                self.send(Some(AnnounceEvent::Completed), transfer).await?;
+            }
+            self.send(Some(AnnounceEvent::Stopped), transfer).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        event: Option<AnnounceEvent>,
+        transfer: Transfer,
+    ) -> anyhow::Result<AnnounceResponse> {
+        self.request.event = event;
+        self.request.uploaded = transfer.uploaded;
+        self.request.downloaded = transfer.downloaded;
+        self.request.left = transfer.left;
+        let response = announce(&self.url, self.info_hash, &self.request);
+        let response = tokio::time::timeout(ANNOUNCE_TIMEOUT, response)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("tracker did not respond")));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                self.next = Instant::now() + RETRY_INTERVAL.max(self.min_interval);
+                return Err(e);
+            }
+        };
+        match event {
+            Some(AnnounceEvent::Started) => self.started = true,
+            Some(AnnounceEvent::Completed) => self.completed = false,
+            _ => {}
+        }
+        self.interval = response.interval.unwrap_or(self.interval);
+        self.min_interval = response.min_interval.unwrap_or(self.min_interval);
+        // The tracker may leave it out of later responses; we keep sending the last one.
+        if response.tracker_id.is_some() {
+            self.request.trackerid = response.tracker_id.clone();
+        }
+        if let Some(warning) = &response.warning {
+            eprintln!("Tracker {} warns: {}", self.url, warning);
+        }
+        self.next = Instant::now() + self.interval.max(self.min_interval);
+        Ok(response)
+    }
+}
+
+// Fires when the tracker is due another announce, never without one.
+pub async fn next_announce(tracker: Option<&TrackerSession>) {
+    match tracker {
+        Some(tracker) => tokio::time::sleep_until(tracker.next_announce()).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub fn parse_compact_peers(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::new(ip, port)
+        })
+        .collect()
+}
+
+pub fn parse_compact_peers6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(18)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)
+        })
+        .collect()
+}
+
+// The address we would reach `probe` from, if it looks like one other peers can
+// reach. Connecting a UDP socket only consults the routing table.
+fn local_address(probe: SocketAddr) -> Option<IpAddr> {
+    let unspecified: IpAddr = match probe {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).ok()?;
+    socket.connect(probe).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    is_public(ip).then_some(ip)
+}
+
+// Leaves out addresses only our own network can reach: loopback, link-local,
+// and the private ranges of RFC 1918 and unique local IPv6 (fc00::/7).
+fn is_public(ip: IpAddr) -> bool {
+    let local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local() || ip.is_private(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local() || ip.is_unique_local(),
+    };
+    !ip.is_loopback() && !ip.is_unspecified() && !local
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn groups_addresses_sharing_a_peer_id() {
+        let mut bytes = b"d5:peersl".to_vec();
+        bytes.extend(b"d2:ip9:127.0.0.17:peer id20:AAAAAAAAAAAAAAAAAAAA4:porti1ee");
+        bytes.extend(b"d2:ip3:::17:peer id20:AAAAAAAAAAAAAAAAAAAA4:porti2ee");
+        bytes.extend(b"d2:ip9:127.0.0.24:porti3ee");
+        bytes.extend(b"d2:ip3:::24:porti4ee");
+        bytes.extend(b"ee");
+        let response = TrackerResponse::from_bytes(&bytes).unwrap();
+        let address = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            response.peer_groups(),
+            [
+                vec![address("127.0.0.1:1"), address("[::1]:2")],
+                vec![address("127.0.0.2:3")],
+                vec![address("[::2]:4")],
+            ]
+        );
+        assert_eq!(response.peers().len(), 4);
+    }
+
+    #[test]
+    fn turns_a_failure_reason_into_an_error() {
+        let error = TrackerResponse::from_bytes(b"d14:failure reason11:unknown keye").unwrap_err();
+        let failure = error.downcast_ref::<TrackerFailure>().unwrap();
+        assert_eq!(failure.reason, "unknown key");
+        assert_eq!(error.to_string(), "tracker failure: unknown key");
+    }
+
+    #[test]
+    fn reads_the_announce_details() {
+        let mut bytes = b"d8:completei12e10:incompletei3e8:intervali1800e".to_vec();
+        bytes.extend(b"12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1");
+        bytes.extend(b"6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00");
+        bytes.extend(b"\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2");
+        bytes.extend(b"10:tracker id3:abc15:warning message4:slowe");
+        let response = AnnounceResponse::from(TrackerResponse::from_bytes(&bytes).unwrap());
+        assert_eq!(response.seeders, Some(12));
+        assert_eq!(response.leechers, Some(3));
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(60)));
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        assert_eq!(
+            response.peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_a_dictionary_peer_list() {
+        let mut bytes = b"d8:intervali900e5:peersl".to_vec();
+        bytes.extend(b"d2:ip8:10.0.0.17:peer id20:AAAAAAAAAAAAAAAAAAAA4:porti6881ee");
+        bytes.extend(b"d2:ip11:example.org4:porti6882ee");
+        bytes.extend(b"d2:ip7:2001::14:porti6883ee");
+        bytes.extend(b"ee");
+        let response = AnnounceResponse::from(TrackerResponse::from_bytes(&bytes).unwrap());
+        assert_eq!(response.seeders, None);
+        assert_eq!(response.warning, None);
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001::1]:6883".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_addresses_others_cannot_reach() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["fd00::1", "fc00::1", "fe80::1", "::1", "::"] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public("203.0.113.7".parse().unwrap()));
+        assert!(is_public("2001:db8::7".parse().unwrap()));
+    }
+
+    #[test]
+    fn sends_only_the_family_the_announce_does_not_use() {
+        let mut request = TrackerRequest::new(0);
+        request.ipv4 = Some(Ipv4Addr::new(203, 0, 113, 7));
+        request.ipv6 = Some("2001:db8::7".parse().unwrap());
+        let v4: SocketAddr = "198.51.100.1:80".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+
+        let over_v4 = request.for_tracker(&[v4]);
+        assert_eq!((over_v4.ipv4, over_v4.ipv6), (None, request.ipv6));
+        let over_v6 = request.for_tracker(&[v6]);
+        assert_eq!((over_v6.ipv4, over_v6.ipv6), (request.ipv4, None));
+        let dual_stack = request.for_tracker(&[v4, v6]);
+        assert_eq!((dual_stack.ipv4, dual_stack.ipv6), (None, None));
+    }
+
+    type Queries = Arc<Mutex<Vec<String>>>;
+
+    // An http tracker on localhost that answers the nth announce with the nth
+    // body, repeating the last, and keeps each query string it got.
+    async fn http_stand_in(bodies: Vec<&'static [u8]>) -> (String, Queries) {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let queries = Queries::default();
+        let log = queries.clone();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    stream.read_exact(&mut byte).await.unwrap();
+                    request.push(byte[0]);
+                }
+                let line = String::from_utf8_lossy(&request);
+                let target = line.split(' ').nth(1).unwrap();
+                log.lock()
+                    .unwrap()
+                    .push(target.split_once('?').unwrap().1.to_string());
+                let body = bodies[n.min(bodies.len() - 1)];
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        (url, queries)
+    }
+
+    fn param(query: &str, key: &str) -> Option<String> {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    fn events(queries: &Queries) -> Vec<Option<String>> {
+        let queries = queries.lock().unwrap();
+        queries.iter().map(|q| param(q, "event")).collect()
+    }
+
+    fn session(url: String) -> TrackerSession {
+        TrackerSession::new(url, [0xaa; 20], TrackerRequest::new(100))
+    }
+
+    #[tokio::test]
+    async fn announces_over_the_life_of_a_download() {
+        let (url, queries) = http_stand_in(vec![
+            b"d8:intervali1800e12:min intervali60e5:peers0:10:tracker id3:abce",
+            b"d8:intervali10e5:peers0:e",
+        ])
+        .await;
+        let mut tracker = session(url);
+        let done = Transfer {
+            left: 0,
+            downloaded: 100,
+            uploaded: 0,
+        };
+
+        tracker.announce(Transfer::default()).await.unwrap();
+        let due = tracker.next_announce() - Instant::now();
+        assert!(due > Duration::from_secs(1790) && due <= Duration::from_secs(1800));
+
+        tracker.announce(Transfer::default()).await.unwrap();
+        // `min interval` still holds though the tracker left it out.
+        let due = tracker.next_announce() - Instant::now();
+        assert!(due > Duration::from_secs(50) && due <= Duration::from_secs(60));
+
+        tracker.complete();
+        assert!(tracker.next_announce() <= Instant::now());
+        tracker.announce(done).await.unwrap();
+        tracker.announce(done).await.unwrap();
+        tracker.stopped(done).await.unwrap();
+
+        let expected = [
+            Some("started"),
+            None,
+            Some("completed"),
+            None,
+            Some("stopped"),
+        ];
+        assert_eq!(events(&queries), expected.map(|e| e.map(String::from)));
+        let queries = queries.lock().unwrap();
+        let ids: Vec<_> = queries.iter().map(|q| param(q, "trackerid")).collect();
+        assert_eq!(ids[0], None);
+        assert!(ids[1..].iter().all(|id| id.as_deref() == Some("abc")));
+        assert_eq!(param(&queries[2], "left").as_deref(), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn sends_started_before_completed_when_started_failed() {
+        let (url, queries) = http_stand_in(vec![
+            b"d14:failure reason4:busye",
+            b"d8:intervali1800e5:peers0:e",
+        ])
+        .await;
+        let mut tracker = session(url);
+
+        assert!(tracker.announce(Transfer::default()).await.is_err());
+        let due = tracker.next_announce() - Instant::now();
+        assert!(due > Duration::from_secs(50) && due <= RETRY_INTERVAL);
+
+        tracker.complete();
+        tracker.announce(Transfer::default()).await.unwrap();
+        tracker.stopped(Transfer::default()).await.unwrap();
+        let expected = ["started", "started", "completed", "stopped"];
+        assert_eq!(events(&queries), expected.map(|e| Some(e.to_string())));
+    }
+
+    #[tokio::test]
+    async fn sends_a_pending_completed_before_stopped() {
+        let (url, queries) = http_stand_in(vec![
+            b"d8:intervali1800e5:peers0:e",
+            b"d14:failure reason4:busye",
+            b"d8:intervali1800e5:peers0:e",
+        ])
+        .await;
+        let mut tracker = session(url);
+
+        tracker.announce(Transfer::default()).await.unwrap();
+        tracker.complete();
+        assert!(tracker.announce(Transfer::default()).await.is_err());
+        tracker.stopped(Transfer::default()).await.unwrap();
+        let expected = ["started", "completed", "completed", "stopped"];
+        assert_eq!(events(&queries), expected.map(|e| Some(e.to_string())));
+    }
+
+    #[tokio::test]
+    async fn stays_silent_on_stop_if_never_started() {
+        let (url, queries) = http_stand_in(vec![b"d14:failure reason4:busye"]).await;
+        let mut tracker = session(url);
+        assert!(tracker.announce(Transfer::default()).await.is_err());
+        tracker.stopped(Transfer::default()).await.unwrap();
+        assert_eq!(events(&queries), [Some("started".to_string())]);
+    }
+}
@@ -0,0 +1,359 @@
+use anyhow::Context;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout};
+use url::Url;
+
+use crate::tracker::{
+    parse_compact_peers, parse_compact_peers6, AnnounceEvent, TrackerFailure, TrackerRequest,
+};
+
+// BEP 15: https://www.bittorrent.org/beps/bep_0015.html
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+const MAX_RETRANSMITS: u32 = 8;
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct UdpAnnounceResponse {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl UdpTracker {
+    pub async fn new(url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(url).context("invalid udp tracker url")?;
+        let host = url.host_str().context("udp tracker url has no host")?;
+        let port = url.port().context("udp tracker url has no port")?;
+        let address = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .context("could not resolve udp tracker host")?;
+        Self::connect_to(address).await
+    }
+
+    pub async fn connect_to(address: SocketAddr) -> anyhow::Result<Self> {
+        let socket = match address {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+        };
+        socket.connect(address).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+        })
+    }
+
+    // Shortens the 15 * 2^n retransmission schedule, e.g. for a tracker on localhost.
+    pub fn with_base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<UdpAnnounceResponse> {
+        for n in 0..=MAX_RETRANSMITS {
+            let connection_id = match self.connection_id() {
+                Some(id) => id,
+                None => match self.connect(n).await? {
+                    Some(id) => id,
+                    None => continue,
+                },
+            };
+
+            let transaction_id = rand::random::<u32>();
+            let mut packet = Vec::with_capacity(98);
+            packet.extend(connection_id.to_be_bytes());
+            packet.extend(ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend(transaction_id.to_be_bytes());
+            packet.extend(info_hash);
+            packet.extend(request.peer_id.as_bytes());
+            packet.extend(request.downloaded.to_be_bytes());
+            packet.extend(request.left.to_be_bytes());
+            packet.extend(request.uploaded.to_be_bytes());
+            let event: u32 = match request.event {
+                None => 0,
+                Some(AnnounceEvent::Completed) => 1,
+                Some(AnnounceEvent::Started) => 2,
+                Some(AnnounceEvent::Stopped) => 3,
+            };
+            packet.extend(event.to_be_bytes());
+            packet.extend(0u32.to_be_bytes()); // ip: let the tracker use the source address
+            packet.extend(request.key.to_be_bytes());
+            packet.extend((-1i32).to_be_bytes()); // num_want: tracker default
+            packet.extend(request.port.to_be_bytes());
+
+            let Some(reply) = self
+                .transact(&packet, ACTION_ANNOUNCE, transaction_id, n)
+                .await?
+            else {
+                continue;
+            };
+            anyhow::ensure!(reply.len() >= 20, "announce response too short");
+            // Over IPv6 the tracker answers with 18-byte IPv6 peers instead.
+            let peers = match self.socket.peer_addr()? {
+                SocketAddr::V4(_) => parse_compact_peers(&reply[20..]),
+                SocketAddr::V6(_) => parse_compact_peers6(&reply[20..]),
+            };
+            return Ok(UdpAnnounceResponse {
+                interval: read_u32(&reply[8..]),
+                leechers: read_u32(&reply[12..]),
+                seeders: read_u32(&reply[16..]),
+                peers,
+            });
+        }
+        Err(anyhow::anyhow!("udp tracker did not respond"))
+    }
+
+    fn connection_id(&self) -> Option<u64> {
+        self.connection
+            .filter(|(_, obtained)| obtained.elapsed() < CONNECTION_ID_TTL)
+            .map(|(id, _)| id)
+    }
+
+    async fn connect(&mut self, n: u32) -> anyhow::Result<Option<u64>> {
+        let transaction_id = rand::random::<u32>();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend(PROTOCOL_ID.to_be_bytes());
+        packet.extend(ACTION_CONNECT.to_be_bytes());
+        packet.extend(transaction_id.to_be_bytes());
+
+        let Some(reply) = self
+            .transact(&packet, ACTION_CONNECT, transaction_id, n)
+            .await?
+        else {
+            return Ok(None);
+        };
+        anyhow::ensure!(reply.len() >= 16, "connect response too short");
+        let connection_id = u64::from_be_bytes(reply[8..16].try_into()?);
+        self.connection = Some((connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    // Sends `packet` and waits for the matching reply. Returns `None` on timeout
+    // so the caller can retransmit with the next `n`.
+    async fn transact(
+        &mut self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        n: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        self.socket.send(packet).await?;
+        let deadline = tokio::time::Instant::now() + self.base_timeout * 2u32.pow(n);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let len = match timeout(remaining, self.socket.recv(&mut buf)).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(None),
+            };
+            let reply = &buf[..len];
+            if reply.len() < 8 || read_u32(&reply[4..]) != transaction_id {
+                // Stale reply to an earlier retransmission, or garbage.
+                continue;
+            }
+            match read_u32(reply) {
+                ACTION_ERROR => {
+                    let reason = String::from_utf8_lossy(&reply[8..]).into_owned();
+                    return Err(TrackerFailure { reason }.into());
+                }
+                a if a == action => return Ok(Some(reply.to_vec())),
+                a => return Err(anyhow::anyhow!("unexpected udp tracker action {}", a)),
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const CONNECTION_ID: u64 = 0x0123_4567_89ab_cdef;
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+
+    type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    // A tracker on localhost that answers each datagram with what `reply`
+    // returns, or drops it on `None`. Keeps every datagram it got.
+    async fn stand_in(
+        mut reply: impl FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> (SocketAddr, Received) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let received = Received::default();
+        let log = received.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let packet = buf[..len].to_vec();
+                log.lock().unwrap().push(packet.clone());
+                if let Some(reply) = reply(&packet) {
+                    socket.send_to(&reply, from).await.unwrap();
+                }
+            }
+        });
+        (address, received)
+    }
+
+    // Connects and announces both carry the action at 8 and the
+    // transaction id at 12.
+    fn action(packet: &[u8]) -> u32 {
+        read_u32(&packet[8..])
+    }
+
+    fn transaction_id(packet: &[u8]) -> &[u8] {
+        &packet[12..16]
+    }
+
+    // Answers like a working tracker with two peers.
+    fn working(packet: &[u8]) -> Option<Vec<u8>> {
+        let mut reply = action(packet).to_be_bytes().to_vec();
+        reply.extend(transaction_id(packet));
+        match action(packet) {
+            ACTION_CONNECT => reply.extend(CONNECTION_ID.to_be_bytes()),
+            ACTION_ANNOUNCE => {
+                assert_eq!(packet[..8], CONNECTION_ID.to_be_bytes());
+                for n in [1800u32, 3, 5] {
+                    reply.extend(n.to_be_bytes());
+                }
+                reply.extend([127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0xc8, 0xd5]);
+            }
+            _ => return None,
+        }
+        Some(reply)
+    }
+
+    fn actions(received: &Received) -> Vec<u32> {
+        received.lock().unwrap().iter().map(|p| action(p)).collect()
+    }
+
+    async fn tracker(address: SocketAddr) -> UdpTracker {
+        UdpTracker::connect_to(address)
+            .await
+            .unwrap()
+            .with_base_timeout(Duration::from_millis(100))
+    }
+
+    #[tokio::test]
+    async fn connects_then_announces() {
+        let (address, received) = stand_in(working).await;
+        let request = TrackerRequest::new(1000).with_port(51413);
+        let response = tracker(address)
+            .await
+            .announce(INFO_HASH, &request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.leechers, 3);
+        assert_eq!(response.seeders, 5);
+        assert_eq!(
+            response.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:51413".parse().unwrap()
+            ]
+        );
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].len(), 16);
+        assert_eq!(received[0][..8], PROTOCOL_ID.to_be_bytes());
+        let announce = &received[1];
+        assert_eq!(announce.len(), 98);
+        assert_eq!(announce[16..36], INFO_HASH);
+        assert_eq!(announce[36..56], *request.peer_id.as_bytes());
+        assert_eq!(announce[64..72], 1000u64.to_be_bytes());
+        assert_eq!(announce[96..98], 51413u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn reuses_connection_id_until_it_expires() {
+        let (address, received) = stand_in(working).await;
+        let request = TrackerRequest::new(0);
+        let mut tracker = tracker(address).await;
+        tracker.announce(INFO_HASH, &request).await.unwrap();
+        tracker.announce(INFO_HASH, &request).await.unwrap();
+        assert_eq!(
+            actions(&received),
+            [ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE]
+        );
+
+        let (id, obtained) = tracker.connection.unwrap();
+        tracker.connection = Some((id, obtained - CONNECTION_ID_TTL));
+        tracker.announce(INFO_HASH, &request).await.unwrap();
+        assert_eq!(actions(&received)[3..], [ACTION_CONNECT, ACTION_ANNOUNCE]);
+    }
+
+    #[tokio::test]
+    async fn retransmits_after_a_dropped_datagram() {
+        let mut dropped = false;
+        let (address, received) = stand_in(move |packet| {
+            if action(packet) == ACTION_ANNOUNCE && !dropped {
+                dropped = true;
+                return None;
+            }
+            working(packet)
+        })
+        .await;
+        let response = tracker(address)
+            .await
+            .announce(INFO_HASH, &TrackerRequest::new(0))
+            .await
+            .unwrap();
+
+        assert_eq!(response.peers.len(), 2);
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received.iter().map(|p| action(p)).collect::<Vec<_>>(),
+            [ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE]
+        );
+        // Each retransmission is a new transaction.
+        assert_ne!(transaction_id(&received[1]), transaction_id(&received[2]));
+    }
+
+    #[tokio::test]
+    async fn reports_error_action() {
+        let (address, _) = stand_in(|packet| {
+            if action(packet) == ACTION_CONNECT {
+                return working(packet);
+            }
+            let mut reply = ACTION_ERROR.to_be_bytes().to_vec();
+            reply.extend(transaction_id(packet));
+            reply.extend(b"torrent not registered");
+            Some(reply)
+        })
+        .await;
+        let error = tracker(address)
+            .await
+            .announce(INFO_HASH, &TrackerRequest::new(0))
+            .await
+            .unwrap_err();
+
+        let failure = error.downcast_ref::<TrackerFailure>().unwrap();
+        assert_eq!(failure.reason, "torrent not registered");
+    }
+}
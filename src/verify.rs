@@ -0,0 +1,203 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+use crate::{
+    download::Downloaded,
+    storage::{FileStorage, Storage},
+    torrent::Info,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PieceStatus {
+    Good,
+    Bad,
+    // Some of the piece's bytes lie in a file that is absent or too short.
+    Missing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub length: u64,
+    pub good: usize,
+    pub bad: usize,
+    pub missing: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.pieces.iter().filter(|s| **s == status).count()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.pieces.iter().all(|s| *s == PieceStatus::Good)
+    }
+}
+
+// Hash-checks the data laid out under `path` the same way `download -o <path>`
+// writes it, without creating or modifying anything.
+pub fn verify(info: &Info, path: &Path) -> anyhow::Result<VerifyReport> {
+    let storage = FileStorage::open_partial(path, info)?;
+    let downloaded = Downloaded::new(info);
+    let mut files: Vec<_> = downloaded
+        .into_iter()
+        .map(|file| FileReport {
+            path: file.path().join("/"),
+            length: file.len(),
+            good: 0,
+            bad: 0,
+            missing: 0,
+        })
+        .collect();
+    let spans: Vec<_> = downloaded
+        .into_iter()
+        .map(|file| (file.offset(), file.offset() + file.len()))
+        .collect();
+
+    let mut pieces = Vec::with_capacity(info.num_pieces());
+    let mut buf = Vec::new();
+    for (piece, hash) in info.pieces().iter().enumerate() {
+        let start = info.piece_offset(piece);
+        let len = info.piece_len(piece) as u64;
+        buf.resize(len as usize, 0);
+
+        // A read error, e.g. a directory where a file should be, is as bad as wrong bytes.
+        let status = if !storage.is_available(start, len) {
+            PieceStatus::Missing
+        } else if storage.read(start, &mut buf).is_ok() && *hash == *Sha1::digest(&buf) {
+            PieceStatus::Good
+        } else {
+            PieceStatus::Bad
+        };
+
+        let touched = spans
+            .iter()
+            .zip(&mut files)
+            .filter(|((from, to), _)| from < to && *from < start + len && *to > start);
+        for (_, file) in touched {
+            match status {
+                PieceStatus::Good => file.good += 1,
+                PieceStatus::Bad => file.bad += 1,
+                PieceStatus::Missing => file.missing += 1,
+            }
+        }
+        pieces.push(status);
+    }
+
+    Ok(VerifyReport { pieces, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::BencodeValue;
+
+    const DATA: &[u8; 12] = b"abcdefghijkl";
+
+    // Two six-byte files with four-byte pieces, so piece 1 straddles them.
+    fn info() -> Info {
+        let file = |name: &str| {
+            BencodeValue::Dict(vec![
+                (b"length".to_vec(), BencodeValue::Int(6)),
+                (
+                    b"path".to_vec(),
+                    BencodeValue::List(vec![BencodeValue::Bytes(name.as_bytes().to_vec())]),
+                ),
+            ])
+        };
+        let pieces = DATA
+            .chunks(4)
+            .flat_map(|c| Sha1::digest(c).to_vec())
+            .collect();
+        let dict = BencodeValue::Dict(vec![
+            (
+                b"files".to_vec(),
+                BencodeValue::List(vec![file("a"), file("b")]),
+            ),
+            (b"name".to_vec(), BencodeValue::Bytes(b"t".to_vec())),
+            (b"piece length".to_vec(), BencodeValue::Int(4)),
+            (b"pieces".to_vec(), BencodeValue::Bytes(pieces)),
+        ]);
+        Info::from_bytes(&dict.encode()).unwrap()
+    }
+
+    fn write(dir: &Path, a: &[u8], b: &[u8]) {
+        std::fs::create_dir_all(dir.join("t")).unwrap();
+        std::fs::write(dir.join("t/a"), a).unwrap();
+        std::fs::write(dir.join("t/b"), b).unwrap();
+    }
+
+    fn counts(report: &VerifyReport) -> Vec<(usize, usize, usize)> {
+        report
+            .files
+            .iter()
+            .map(|f| (f.good, f.bad, f.missing))
+            .collect()
+    }
+
+    #[test]
+    fn reports_good_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &DATA[..6], &DATA[6..]);
+        let report = verify(&info(), dir.path()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(counts(&report), [(2, 0, 0), (2, 0, 0)]);
+    }
+
+    #[test]
+    fn reports_a_corrupt_piece_against_both_files_it_spans() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &DATA[..6], b"Ghijkl");
+        let report = verify(&info(), dir.path()).unwrap();
+        use PieceStatus::*;
+        assert_eq!(report.pieces, [Good, Bad, Good]);
+        assert_eq!(counts(&report), [(1, 1, 0), (1, 1, 0)]);
+    }
+
+    #[test]
+    fn reports_pieces_of_missing_and_short_files_as_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &DATA[..6], &DATA[6..]);
+        std::fs::remove_file(dir.path().join("t/b")).unwrap();
+        let report = verify(&info(), dir.path()).unwrap();
+        use PieceStatus::*;
+        assert_eq!(report.pieces, [Good, Missing, Missing]);
+
+        write(dir.path(), &DATA[..3], &DATA[6..]);
+        let report = verify(&info(), dir.path()).unwrap();
+        assert_eq!(report.pieces, [Missing, Missing, Good]);
+        assert_eq!(counts(&report), [(0, 0, 2), (1, 0, 1)]);
+    }
+
+    #[test]
+    fn reports_pieces_of_a_directory_in_place_of_a_file_as_bad() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("t/b")).unwrap();
+        std::fs::write(dir.path().join("t/a"), &DATA[..6]).unwrap();
+        let report = verify(&info(), dir.path()).unwrap();
+        use PieceStatus::*;
+        assert_eq!(report.pieces, [Good, Bad, Bad]);
+    }
+
+    #[test]
+    fn serializes_the_report() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &DATA[..6], b"Ghijkl");
+        std::fs::remove_file(dir.path().join("t/a")).unwrap();
+        let report = verify(&info(), dir.path()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"pieces":["missing","missing","good"],"files":["#.to_string()
+                + r#"{"path":"a","length":6,"good":0,"bad":0,"missing":2},"#
+                + r#"{"path":"b","length":6,"good":1,"bad":0,"missing":1}]}"#
+        );
+    }
+}